@@ -0,0 +1,530 @@
+//! Working out how likely each result of an expression is.
+//!
+//! Most expressions are worked out exactly, by convolving the distributions of their
+//! parts. Dice whose modifiers make each die depend on the others, like keeping the
+//! highest or exploding, are instead estimated by rolling the whole expression many
+//! times, as are expressions too large to work out in reasonable time.
+
+use std::collections::BTreeMap;
+
+use dice::{self, floor_div, BinOp, Compare, CritDamage, DiceExpr, ErrorKind, EvalOptions, Expr, Faces,
+           Modifier, Mode, Reroll, RollError, Term, MAX_DICE};
+use rng;
+use roll::{self, RollOptions};
+
+/// The most times an expression is rolled when it can't be worked out exactly
+pub const SAMPLES: u32 = 100_000;
+
+/// Roughly the most dice rolled in estimating an expression, counting every explosion
+/// and reroll. Expressions that roll many dice are rolled fewer times.
+const MAX_DICE_ROLLED: u64 = 2_000_000;
+
+/// The most pairs of values combined in working out a single step exactly, beyond
+/// which the expression is estimated instead
+const MAX_WORK: usize = 50_000_000;
+
+/// The widest range of values a step worked out exactly may cover
+const MAX_WIDTH: usize = 1_000_000;
+
+/// How likely each value of an expression is
+#[derive(Debug, Clone, PartialEq)]
+pub struct Distribution {
+    /// Every possible value and its probability, lowest value first. Values that
+    /// can't happen are left out.
+    values: Vec<(i32, f64)>,
+    /// Whether the probabilities were worked out exactly rather than estimated
+    exact: bool,
+}
+
+impl Distribution {
+    /// The distribution of an expression that always has the same value
+    fn constant(value: i32) -> Distribution {
+        Distribution { values: vec![(value, 1.0)], exact: true }
+    }
+
+    /// A distribution from the weight of each value, which needn't add up to one.
+    fn from_weights(weights: BTreeMap<i32, f64>, exact: bool) -> Distribution {
+        let total: f64 = weights.values().sum();
+        let values = weights.into_iter()
+            .filter(|&(_, weight)| weight > 0.0)
+            .map(|(value, weight)| (value, weight / total))
+            .collect();
+        Distribution { values, exact }
+    }
+
+    /// The lowest possible value
+    pub fn min(&self) -> i32 {
+        self.values.first().map(|&(value, _)| value).unwrap_or(0)
+    }
+
+    /// The highest possible value
+    pub fn max(&self) -> i32 {
+        self.values.last().map(|&(value, _)| value).unwrap_or(0)
+    }
+
+    /// The average value
+    pub fn mean(&self) -> f64 {
+        self.values.iter().map(|&(value, p)| f64::from(value) * p).sum()
+    }
+
+    /// How spread out the values are: the average squared distance from the mean
+    pub fn variance(&self) -> f64 {
+        let mean = self.mean();
+        self.values.iter().map(|&(value, p)| (f64::from(value) - mean).powi(2) * p).sum()
+    }
+
+    /// The square root of the variance, in the same units as the values
+    pub fn std_dev(&self) -> f64 {
+        self.variance().sqrt()
+    }
+
+    /// The chance of rolling exactly `value`
+    pub fn probability(&self, value: i32) -> f64 {
+        match self.values.binary_search_by_key(&value, |&(v, _)| v) {
+            Ok(i) => self.values[i].1,
+            Err(_) => 0.0,
+        }
+    }
+
+    /// The chance of rolling `value` or more
+    pub fn at_least(&self, value: i32) -> f64 {
+        self.values.iter().filter(|&&(v, _)| v >= value).fold(0.0, |sum, &(_, p)| sum + p)
+    }
+
+    /// The chance of rolling `value` or less
+    pub fn at_most(&self, value: i32) -> f64 {
+        self.values.iter().filter(|&&(v, _)| v <= value).fold(0.0, |sum, &(_, p)| sum + p)
+    }
+
+    /// Whether the probabilities are exact, rather than estimated from many rolls
+    pub fn is_exact(&self) -> bool {
+        self.exact
+    }
+
+    /// Every possible value and its probability, lowest value first
+    pub fn iter(&self) -> impl Iterator<Item = (i32, f64)> + '_ {
+        self.values.iter().cloned()
+    }
+
+    fn negate(&self, span: dice::Span) -> Result<Distribution, RollError> {
+        let mut values = Vec::with_capacity(self.values.len());
+        for &(value, p) in self.values.iter().rev() {
+            let negated = value.checked_neg().ok_or_else(|| RollError::new(ErrorKind::Overflow, span))?;
+            values.push((negated, p));
+        }
+        Ok(Distribution { values, exact: self.exact })
+    }
+}
+
+/// What an expression is likely to roll
+#[derive(Debug, Clone, PartialEq)]
+pub struct Analysis {
+    /// How likely each value of the roll is. For a check, like `1d20 + 6 >= 17`, this
+    /// is the distribution of the roll, not of the target.
+    pub distribution: Distribution,
+    /// The chance of passing, if the expression is a check
+    pub pass_chance: Option<f64>,
+}
+
+/// Work out how likely each result of an expression is.
+///
+/// Macros, like `#longsword`, are expanded first. Fails if the expression doesn't
+/// parse, or if it could fail when rolled, like `1d6 / (1d2 - 1)`, which sometimes
+/// divides by zero.
+pub fn analyze(s: &str, options: &RollOptions) -> Result<Analysis, roll::RollError> {
+    let parsed = roll::parse(s, options)?;
+    let options = &EvalOptions { mode: parsed.mode.unwrap_or(options.eval.mode), ..options.eval.clone() };
+    match options.mode {
+        Mode::Normal | Mode::Critical => match exact_analysis(&parsed.expr, options)? {
+            Some(analysis) => Ok(analysis),
+            None => Ok(simulate(&parsed.expr, options)?),
+        },
+        // Dice showing set faces always give the same result
+        Mode::Average | Mode::Maximize => {
+            let evaluation = dice::evaluate(&parsed.expr, &mut rng::seeded(0), options)?;
+            Ok(Analysis {
+                distribution: Distribution::constant(evaluation.value),
+                pass_chance: evaluation.check.map(|check| if check.passed { 1.0 } else { 0.0 }),
+            })
+        },
+    }
+}
+
+/// Work out an expression exactly, or `None` if it needs estimating.
+fn exact_analysis(expr: &Expr, options: &EvalOptions) -> Result<Option<Analysis>, RollError> {
+    if let Expr::Check { ref roll, op, ref target, .. } = *expr {
+        let (roll, target) = match (exact(roll, options)?, exact(target, options)?) {
+            (Some(roll), Some(target)) => (roll, target),
+            _ => return Ok(None),
+        };
+        if roll.values.len().saturating_mul(target.values.len()) > MAX_WORK {
+            return Ok(None);
+        }
+        let mut pass_chance = 0.0;
+        for &(r, p) in &roll.values {
+            for &(t, q) in &target.values {
+                if (Compare { op, target: t }).matches(r) {
+                    pass_chance += p * q;
+                }
+            }
+        }
+        return Ok(Some(Analysis { distribution: roll, pass_chance: Some(pass_chance) }));
+    }
+    Ok(exact(expr, options)?.map(|distribution| Analysis { distribution, pass_chance: None }))
+}
+
+/// Work out the distribution of an expression exactly, or `None` if it needs estimating.
+fn exact(expr: &Expr, options: &EvalOptions) -> Result<Option<Distribution>, RollError> {
+    match *expr {
+        Expr::Number { value, .. } => Ok(Some(Distribution::constant(value))),
+        Expr::Dice(ref dice) => dice_distribution(dice, options),
+        Expr::Negate { ref operand, span } => match exact(operand, options)? {
+            Some(operand) => operand.negate(span).map(Some),
+            None => Ok(None),
+        },
+        Expr::Binary { op, ref lhs, ref rhs, span } => {
+            let (left, right) = match (exact(lhs, options)?, exact(rhs, options)?) {
+                (Some(left), Some(right)) => (left, right),
+                _ => return Ok(None),
+            };
+            match op {
+                BinOp::Add => add(&left, &right, span),
+                BinOp::Sub => add(&left, &right.negate(span)?, span),
+                BinOp::Mul => combine(&left, &right, span, |a, b| a.checked_mul(b)),
+                BinOp::Div => {
+                    if right.probability(0) > 0.0 {
+                        return Err(RollError::new(ErrorKind::DivisionByZero, rhs.span()));
+                    }
+                    combine(&left, &right, span, floor_div)
+                },
+            }
+        },
+        Expr::Group { ref inner, .. } | Expr::Tagged { ref inner, .. } => exact(inner, options),
+        Expr::Variable { ref name, span } => match dice::resolve(expr, options) {
+            Expr::Variable { .. } => Err(RollError::new(ErrorKind::UnknownVariable(name.clone()), span)),
+            resolved => exact(&resolved, options),
+        },
+        Expr::Check { ref roll, .. } => exact(roll, options),
+    }
+}
+
+/// The distribution of a group of dice, or `None` if it needs estimating. For a
+/// critical hit, the dice are doubled, or a maximized die is added for each one.
+fn dice_distribution(dice: &DiceExpr, options: &EvalOptions) -> Result<Option<Distribution>, RollError> {
+    let faces = match dice.faces {
+        Faces::Named(ref name) => options.dice.get(name)
+            .ok_or_else(|| RollError::new(ErrorKind::UnknownDice(name.clone()), dice.span))?,
+        ref faces => faces,
+    };
+    if dice.count > MAX_DICE || faces.sides() > i32::MAX as u32 {
+        return Err(RollError::new(ErrorKind::Overflow, dice.span));
+    }
+    if faces.sides() as usize > MAX_WIDTH {
+        return Ok(None);
+    }
+
+    let mut rerolls = Vec::new();
+    let mut successes = Vec::new();
+    let mut failures = Vec::new();
+    for modifier in &dice.modifiers {
+        match *modifier {
+            Modifier::Reroll(reroll, on) => rerolls.push((reroll, on)),
+            Modifier::Success(on) => successes.push(on),
+            Modifier::Failure(on) => failures.push(on),
+            // These make each die depend on the others, or on how many times it exploded
+            Modifier::Keep(..) | Modifier::Drop(..) | Modifier::Explode(..) => return Ok(None),
+        }
+    }
+
+    // The chance of each face of a single die
+    let mut weights = BTreeMap::new();
+    match *faces {
+        Faces::Numbered(sides) => for face in 1..=sides as i32 {
+            weights.insert(face, 1.0);
+        },
+        Faces::Fudge => for face in -1..=1 {
+            weights.insert(face, 1.0);
+        },
+        Faces::Custom(ref faces) => for face in faces {
+            *weights.entry(face.value).or_insert(0.0) += 1.0;
+        },
+        Faces::Named(_) => return Ok(None),
+    }
+    let mut die = Distribution::from_weights(weights, true);
+    let (count, maximized) = match (options.mode, options.crit_damage) {
+        (Mode::Critical, CritDamage::DoubleDice) => (dice.count.saturating_mul(2), 0),
+        (Mode::Critical, CritDamage::MaxPlusRoll) => (dice.count, dice.count),
+        _ => (dice.count, 0),
+    };
+    if count > MAX_DICE {
+        return Err(RollError::new(ErrorKind::Overflow, dice.span));
+    }
+    // Maximized dice are never rerolled, but still count in a pool
+    let mut highest = Distribution::constant(die.max());
+
+    if !rerolls.is_empty() {
+        die = match reroll(&die, &rerolls) {
+            Some(die) => die,
+            // Every face would be rerolled forever; rolling it reports why
+            None => return Ok(None),
+        };
+    }
+
+    // In a pool, each die counts one for a success and takes one away for a failure
+    if !successes.is_empty() || !failures.is_empty() {
+        let pool = |die: &Distribution| {
+            let mut counts = BTreeMap::new();
+            for &(face, p) in &die.values {
+                let success = successes.iter().any(|on| on.matches(face)) as i32;
+                let failure = failures.iter().any(|on| on.matches(face)) as i32;
+                *counts.entry(success - failure).or_insert(0.0) += p;
+            }
+            Distribution::from_weights(counts, true)
+        };
+        die = pool(&die);
+        highest = pool(&highest);
+    }
+
+    let (rolled, maximized) = match (sum(die, count, dice.span)?, sum(highest, maximized, dice.span)?) {
+        (Some(rolled), Some(maximized)) => (rolled, maximized),
+        _ => return Ok(None),
+    };
+    add(&maximized, &rolled, dice.span)
+}
+
+/// The distribution of the sum of `count` independent dice, or `None` if it would
+/// take too long to work out.
+fn sum(mut die: Distribution, mut count: u32, span: dice::Span) -> Result<Option<Distribution>, RollError> {
+    // Add up the dice by doubling, so a hundred dice take a handful of steps
+    let mut total = Distribution::constant(0);
+    while count > 0 {
+        if count & 1 == 1 {
+            total = match add(&total, &die, span)? {
+                Some(total) => total,
+                None => return Ok(None),
+            };
+        }
+        count >>= 1;
+        if count > 0 {
+            die = match add(&die, &die, span)? {
+                Some(die) => die,
+                None => return Ok(None),
+            };
+        }
+    }
+    Ok(Some(total))
+}
+
+/// The distribution of a die after rerolling. On the first roll, a die is rerolled if
+/// any reroll matches it; after that, only if one that repeats does. Returns `None`
+/// if the die would never stop rerolling.
+fn reroll(die: &Distribution, rerolls: &[(Reroll, Compare)]) -> Option<Distribution> {
+    let first = |face: i32| rerolls.iter().any(|&(_, on)| on.matches(face));
+    let again = |face: i32| rerolls.iter().any(|&(reroll, on)| reroll == Reroll::Always && on.matches(face));
+
+    let rerolled: f64 = die.values.iter().filter(|&&(face, _)| first(face)).map(|&(_, p)| p).sum();
+    let settled: f64 = die.values.iter().filter(|&&(face, _)| !again(face)).map(|&(_, p)| p).sum();
+    if settled == 0.0 {
+        return None;
+    }
+
+    // A rerolled die keeps rolling until it lands on a face that doesn't repeat
+    let mut weights = BTreeMap::new();
+    for &(face, p) in &die.values {
+        let mut weight = if first(face) { 0.0 } else { p };
+        if !again(face) {
+            weight += rerolled * p / settled;
+        }
+        weights.insert(face, weight);
+    }
+    Some(Distribution::from_weights(weights, true))
+}
+
+/// The distribution of the sum of two independent values, or `None` if it would take
+/// too long to work out.
+fn add(a: &Distribution, b: &Distribution, span: dice::Span) -> Result<Option<Distribution>, RollError> {
+    if a.values.len().saturating_mul(b.values.len()) > MAX_WORK {
+        return Ok(None);
+    }
+    let overflow = || RollError::new(ErrorKind::Overflow, span);
+    let min = a.min().checked_add(b.min()).ok_or_else(overflow)?;
+    let max = a.max().checked_add(b.max()).ok_or_else(overflow)?;
+    let width = (i64::from(max) - i64::from(min) + 1) as usize;
+    if width > MAX_WIDTH {
+        return combine(a, b, span, |x, y| x.checked_add(y));
+    }
+
+    let mut sums = vec![0.0; width];
+    for &(x, p) in &a.values {
+        for &(y, q) in &b.values {
+            sums[(i64::from(x) + i64::from(y) - i64::from(min)) as usize] += p * q;
+        }
+    }
+    let values = sums.into_iter().enumerate()
+        .filter(|&(_, p)| p > 0.0)
+        .map(|(i, p)| (min + i as i32, p))
+        .collect();
+    Ok(Some(Distribution { values, exact: a.exact && b.exact }))
+}
+
+/// The distribution of `f` applied to two independent values, or `None` if it would
+/// take too long to work out. `f` returns `None` when the result overflows.
+fn combine<F>(a: &Distribution, b: &Distribution, span: dice::Span, f: F) -> Result<Option<Distribution>, RollError>
+    where F: Fn(i32, i32) -> Option<i32>
+{
+    if a.values.len().saturating_mul(b.values.len()) > MAX_WORK {
+        return Ok(None);
+    }
+    let mut weights = BTreeMap::new();
+    for &(x, p) in &a.values {
+        for &(y, q) in &b.values {
+            let value = f(x, y).ok_or_else(|| RollError::new(ErrorKind::Overflow, span))?;
+            *weights.entry(value).or_insert(0.0) += p * q;
+        }
+    }
+    Ok(Some(Distribution::from_weights(weights, a.exact && b.exact)))
+}
+
+/// Estimate the distribution of an expression by rolling it up to `SAMPLES` times,
+/// stopping early once `MAX_DICE_ROLLED` dice have been rolled. The rolls always start
+/// from the same seed, so the estimate doesn't change between runs.
+fn simulate(expr: &Expr, options: &EvalOptions) -> Result<Analysis, RollError> {
+    let mut rng = rng::seeded(0);
+    let mut counts = BTreeMap::new();
+    let mut samples = 0u32;
+    let mut passes = 0u32;
+    let mut rolled = 0u64;
+    while samples < SAMPLES && rolled < MAX_DICE_ROLLED {
+        let evaluation = dice::evaluate(expr, &mut rng, options)?;
+        samples += 1;
+        rolled += dice_rolled(&evaluation.terms);
+        *counts.entry(evaluation.value).or_insert(0.0) += 1.0;
+        if evaluation.check.is_some_and(|check| check.passed) {
+            passes += 1;
+        }
+    }
+
+    let pass_chance = match *expr {
+        Expr::Check { .. } => Some(f64::from(passes) / f64::from(samples)),
+        _ => None,
+    };
+    Ok(Analysis { distribution: Distribution::from_weights(counts, false), pass_chance })
+}
+
+/// How many dice a roll rolled, counting every explosion and reroll.
+fn dice_rolled(terms: &[Term]) -> u64 {
+    terms.iter()
+        .filter_map(|term| match *term {
+            Term::Dice(ref dice) => Some(dice.dice.iter()),
+            _ => None,
+        })
+        .flatten()
+        .map(|die| 1 + die.rerolled.len() as u64 + die.chain.len().saturating_sub(1) as u64)
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn analysis(s: &str) -> Analysis {
+        analyze(s, &RollOptions::default()).unwrap()
+    }
+
+    fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+        assert!((actual - expected).abs() <= tolerance, "{} isn't within {} of {}", actual, tolerance, expected);
+    }
+
+    /// Check that estimating an expression gives about what working it out does
+    fn assert_estimate_matches(s: &str) {
+        let options = EvalOptions::default();
+        let exact = analysis(s);
+        assert!(exact.distribution.is_exact());
+        let estimate = simulate(&dice::parse(s).unwrap(), &options).unwrap();
+        assert!(!estimate.distribution.is_exact());
+        // The rarest values may never come up, but nothing impossible should
+        assert!(estimate.distribution.min() >= exact.distribution.min());
+        assert!(estimate.distribution.max() <= exact.distribution.max());
+        assert_close(estimate.distribution.mean(), exact.distribution.mean(), 0.05);
+        assert_close(estimate.distribution.variance(), exact.distribution.variance(), 0.2);
+        for (value, p) in exact.distribution.iter() {
+            assert_close(estimate.distribution.probability(value), p, 0.01);
+        }
+        if let Some(pass_chance) = exact.pass_chance {
+            assert_close(estimate.pass_chance.unwrap(), pass_chance, 0.01);
+        }
+    }
+
+    #[test]
+    fn sums_of_dice_are_exact() {
+        let distribution = analysis("2d6").distribution;
+        assert!(distribution.is_exact());
+        assert_eq!((distribution.min(), distribution.max()), (2, 12));
+        assert_close(distribution.mean(), 7.0, 1e-9);
+        assert_close(distribution.variance(), 35.0 / 6.0, 1e-9);
+        assert_close(distribution.probability(7), 6.0 / 36.0, 1e-9);
+        assert_close(distribution.at_least(11), 3.0 / 36.0, 1e-9);
+    }
+
+    #[test]
+    fn checks_are_exact() {
+        let analysis = analysis("1d20 + 5 vs 15");
+        assert!(analysis.distribution.is_exact());
+        assert_close(analysis.pass_chance.unwrap(), 0.55, 1e-9);
+    }
+
+    #[test]
+    fn critical_hits_are_exact() {
+        let distribution = analysis("2d6 crit").distribution;
+        assert!(distribution.is_exact());
+        assert_eq!((distribution.min(), distribution.max()), (4, 24));
+        assert_close(distribution.mean(), 14.0, 1e-9);
+
+        let mut options = RollOptions::default();
+        options.eval.crit_damage = CritDamage::MaxPlusRoll;
+        let distribution = analyze("2d6 crit", &options).unwrap().distribution;
+        assert!(distribution.is_exact());
+        assert_eq!((distribution.min(), distribution.max()), (14, 24));
+        assert_close(distribution.mean(), 19.0, 1e-9);
+    }
+
+    #[test]
+    fn set_faces_are_constant() {
+        assert_eq!(analysis("2d6 + 3 avg").distribution.iter().collect::<Vec<_>>(), vec![(10, 1.0)]);
+        assert_eq!(analysis("2d6 + 3 max").distribution.iter().collect::<Vec<_>>(), vec![(15, 1.0)]);
+    }
+
+    #[test]
+    fn keeping_dice_is_estimated() {
+        let distribution = analysis("2d20kh").distribution;
+        assert!(!distribution.is_exact());
+        // The chance of the highest of two d20s being at least 11 is 1 - (10/20)²
+        assert_close(distribution.at_least(11), 0.75, 0.01);
+    }
+
+    #[test]
+    fn estimates_agree_with_exact_results() {
+        assert_estimate_matches("2d6 + 3");
+        assert_estimate_matches("1d20 + 5 >= 15");
+        assert_estimate_matches("8d10>=8f1");
+        assert_estimate_matches("1d6 * 2 - 1d4");
+    }
+
+    #[test]
+    fn estimates_stop_at_the_dice_budget() {
+        // Each die explodes five times in six, so every roll rolls about 60 dice
+        let estimate = simulate(&dice::parse("10d6!>1").unwrap(), &EvalOptions::default()).unwrap();
+        assert!(!estimate.distribution.is_exact());
+        // A value that came up once has a probability of one over the rolls made
+        let rarest = estimate.distribution.iter().map(|(_, p)| p).fold(1.0, f64::min);
+        let rolls = (1.0 / rarest).round() as u64;
+        assert!(rolls < u64::from(SAMPLES));
+        assert!(rolls <= MAX_DICE_ROLLED / 10);
+    }
+
+    #[test]
+    fn possible_division_by_zero_is_an_error() {
+        let e = analyze("1d6 / (1d2 - 1)", &RollOptions::default()).unwrap_err();
+        assert_eq!(e.kind, roll::ErrorKind::Dice(ErrorKind::DivisionByZero));
+    }
+}
@@ -0,0 +1,314 @@
+//! The command line front end, for rolling from scripts and terminals.
+
+use std::io::{self, BufRead};
+use std::sync::Arc;
+
+use d20roll::format;
+use d20roll::rng::SeedSequence;
+use d20roll::roll::{definition, roll_batch, Definition, RollError, RollOptions, RollOutcome};
+use d20roll::settings::Settings;
+use d20roll::tables;
+
+const USAGE: &str = "\
+Usage: d20roll roll [OPTIONS] [EXPRESSION...]
+
+Rolls each EXPRESSION and prints the result. With no expressions, reads
+one expression per line from standard input. An expression can hold several
+rolls separated by `;`, and a roll can be repeated, like `6x 4d6dl1`.
+An expression like `@hit = d{miss=0,hit=1,crit=2}` names a kind of die
+instead, and saves it to the settings so it can be rolled like `3@hit`.
+
+Options:
+  -n, --times N        Roll each expression N times, at least once (default 1)
+  -f, --format FORMAT  Print results as `text` (default) or `json`
+  -s, --seed SEED      Roll from SEED, so the same expressions roll the same
+                       dice every time (default from the settings, or random)
+  -p, --profile NAME   Use the variables of the character NAME from the settings
+  -h, --help           Print this message";
+
+/// How outcomes and errors are printed
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    /// `2d6 + 3 = 10 (2d6 [3, 4] + 3)`
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// The options given to the `roll` command
+#[derive(Debug)]
+struct Options {
+    times: u32,
+    format: Format,
+    seed: Option<u64>,
+    profile: Option<String>,
+    expressions: Vec<String>,
+}
+
+/// Run the command line front end with the given arguments, returning the exit code.
+///
+/// The exit code is 0 if everything rolled, 1 if any expression failed to roll,
+/// and 2 if the arguments themselves were wrong.
+pub fn run(args: &[String]) -> i32 {
+    match args.first().map(|a| a.as_str()) {
+        Some("roll") => {},
+        Some("help") | Some("-h") | Some("--help") => {
+            println!("{}", USAGE);
+            return 0;
+        },
+        Some(other) => {
+            eprintln!("d20roll: unknown command `{}`\n\n{}", other, USAGE);
+            return 2;
+        },
+        None => {
+            eprintln!("{}", USAGE);
+            return 2;
+        },
+    }
+
+    let options = match parse_options(&args[1..]) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return 0;
+        },
+        Err(message) => {
+            eprintln!("d20roll: {}\n\n{}", message, USAGE);
+            return 2;
+        },
+    };
+
+    let mut settings = Settings::load().unwrap_or_else(|e| {
+        eprintln!("d20roll: couldn't read the settings, using the defaults: {}", e);
+        Settings::default()
+    });
+    let mut eval_options = settings.eval_options();
+    if let Some(ref profile) = options.profile {
+        if !settings.profiles.contains_key(profile) {
+            eprintln!("d20roll: no character named `{}` in the settings", profile);
+            return 2;
+        }
+        eval_options.variables = settings.variables(Some(profile));
+    }
+    let (tables, errors) = tables::load(&eval_options);
+    for e in errors {
+        eprintln!("d20roll: couldn't load a table: {}", e);
+    }
+    let mut roll_options = RollOptions { eval: eval_options, tables: Arc::new(tables) };
+
+    // Seed from the command line, then the settings, then at random.
+    let seed = options.seed.or(settings.seed);
+    let mut seeds = match seed {
+        Some(seed) => SeedSequence::new(seed),
+        None => SeedSequence::from_entropy(),
+    };
+
+    let mut failed = false;
+    if options.expressions.is_empty() {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    eprintln!("d20roll: couldn't read standard input: {}", e);
+                    return 1;
+                },
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            failed |= !run_expression(&line, &options, &mut settings, &mut roll_options, &mut seeds);
+        }
+    } else {
+        for expression in &options.expressions {
+            failed |= !run_expression(expression, &options, &mut settings, &mut roll_options, &mut seeds);
+        }
+    }
+
+    if failed { 1 } else { 0 }
+}
+
+/// Parse the arguments after `roll`. Returns `None` if help was requested.
+fn parse_options(args: &[String]) -> Result<Option<Options>, String> {
+    let mut options = Options {
+        times: 1,
+        format: Format::Text,
+        seed: None,
+        profile: None,
+        expressions: Vec::new(),
+    };
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        // Accept both `--times 5` and `--times=5`
+        let (flag, inline_value) = match arg.find('=') {
+            Some(i) if arg.starts_with("--") => (&arg[..i], Some(arg[i + 1..].to_string())),
+            _ => (arg.as_str(), None),
+        };
+        let mut value = |name: &str| match inline_value.clone() {
+            Some(v) => Ok(v),
+            None => args.next().cloned().ok_or_else(|| format!("{} needs a value", name)),
+        };
+
+        match flag {
+            "-h" | "--help" => return Ok(None),
+            "-n" | "--times" => {
+                let times = value(flag)?;
+                options.times = times.parse().ok()
+                    .filter(|&times| times >= 1)
+                    .ok_or_else(|| format!("`{}` is not a valid number of times", times))?;
+            },
+            "-s" | "--seed" => {
+                let seed = value(flag)?;
+                options.seed = Some(seed.parse()
+                    .map_err(|_| format!("`{}` is not a valid seed", seed))?);
+            },
+            "-p" | "--profile" => options.profile = Some(value(flag)?),
+            "-f" | "--format" => {
+                options.format = match value(flag)?.as_str() {
+                    "text" => Format::Text,
+                    "json" => Format::Json,
+                    other => return Err(format!("unknown format `{}`", other)),
+                };
+            },
+            // Everything after `--` is an expression, even if it looks like a flag
+            "--" => {
+                options.expressions.extend(args.cloned());
+                break;
+            },
+            _ if flag.starts_with("--") => return Err(format!("unknown option `{}`", flag)),
+            // Anything else, including negative expressions like `-1d4`, is an expression
+            _ => options.expressions.push(arg.clone()),
+        }
+    }
+
+    Ok(Some(options))
+}
+
+/// Define the named die an expression defines, if it's a definition, or roll it.
+/// Returns false if it failed.
+fn run_expression(expression: &str, options: &Options, settings: &mut Settings, roll_options: &mut RollOptions,
+                  seeds: &mut SeedSequence) -> bool {
+    match definition(expression) {
+        Some(Ok(definition)) => {
+            define(definition, options, settings, roll_options);
+            true
+        },
+        Some(Err(error)) => {
+            print_error(expression, &error, options.format);
+            false
+        },
+        None => roll_expression(expression, options, roll_options, seeds),
+    }
+}
+
+/// Name a kind of die for the rest of the expressions, and save it to the settings.
+/// It can still be rolled by name if it can't be saved.
+fn define(definition: Definition, options: &Options, settings: &mut Settings, roll_options: &mut RollOptions) {
+    let Definition { name, faces } = definition;
+    match options.format {
+        Format::Text => println!("@{} = d{}", name, faces),
+        Format::Json => println!("{}", json!({ "name": name, "dice": faces })),
+    }
+    roll_options.eval.dice.insert(name.to_string(), faces.clone());
+    if let Err(e) = settings.save_dice(name, faces) {
+        eprintln!("d20roll: couldn't save `@{}` to the settings: {}", name, e);
+    }
+}
+
+/// Roll an expression as many times as requested, printing each outcome.
+/// Returns false if it failed to roll.
+fn roll_expression(expression: &str, options: &Options, roll_options: &RollOptions, seeds: &mut SeedSequence) -> bool {
+    for _ in 0..options.times {
+        match roll_batch(expression, seeds.next_seed(), roll_options) {
+            Ok(outcomes) => {
+                for outcome in &outcomes {
+                    print_outcome(outcome, options.format);
+                }
+                // A batch of rolls ends with its result as a whole, if it has one
+                let outcomes: Vec<&RollOutcome> = outcomes.iter().collect();
+                match (outcomes[0].batch.as_ref(), format::group_result(&outcomes)) {
+                    (Some(batch), Some(result)) if options.format == Format::Text =>
+                        println!("{} = {} {}", batch.input, result, format::group_breakdown(&outcomes)),
+                    _ => {},
+                }
+            },
+            Err(error) => {
+                print_error(expression, &error, options.format);
+                return false;
+            },
+        }
+    }
+    true
+}
+
+fn print_outcome(outcome: &RollOutcome, format: Format) {
+    match format {
+        Format::Text => println!("{}", format::summary(outcome)),
+        Format::Json => println!("{}", json!({
+            "descriptor": outcome.descriptor,
+            "macro": outcome.macro_name,
+            "resolved": outcome.resolved,
+            "mode": outcome.mode,
+            "outcome": outcome.outcome,
+            "text": outcome.text,
+            "breakdown": outcome.breakdown(),
+            "seed": outcome.seed,
+            "pool": outcome.pool,
+            "check": outcome.check,
+            "critical": outcome.critical,
+            "fumble": outcome.fumble,
+            "subtotals": outcome.subtotals,
+            "batch": outcome.batch,
+        })),
+    }
+}
+
+/// Print an error to standard error, pointing at the part of the expression responsible.
+fn print_error(expression: &str, error: &RollError, format: Format) {
+    match format {
+        Format::Text => {
+            let start = expression[..error.span.start.min(expression.len())].chars().count();
+            let end = expression[..error.span.end.min(expression.len())].chars().count();
+            eprintln!("error: {}", error.kind);
+            eprintln!("  {}", expression);
+            eprintln!("  {}{}", " ".repeat(start), "^".repeat((end - start).max(1)));
+        },
+        Format::Json => eprintln!("{}", json!({
+            "expression": expression,
+            "error": error.kind.to_string(),
+            "start": error.span.start,
+            "end": error.span.end,
+        })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Option<Options>, String> {
+        parse_options(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn options_and_expressions() {
+        let options = parse(&["--times=3", "-f", "json", "-s", "42", "1d20", "-1d4", "--", "--odd"]).unwrap().unwrap();
+        assert_eq!(options.times, 3);
+        assert_eq!(options.format, Format::Json);
+        assert_eq!(options.seed, Some(42));
+        assert_eq!(options.expressions, vec!["1d20", "-1d4", "--odd"]);
+        assert!(parse(&["--help"]).unwrap().is_none());
+    }
+
+    #[test]
+    fn wrong_arguments_are_refused() {
+        assert!(parse(&["--times", "0"]).is_err());
+        assert!(parse(&["-n", "-2"]).is_err());
+        assert!(parse(&["--times"]).is_err());
+        assert!(parse(&["--format", "xml"]).is_err());
+        assert!(parse(&["--loud"]).is_err());
+        assert_eq!(run(&["roll".to_string(), "--times".to_string(), "0".to_string()]), 2);
+        assert_eq!(run(&["dance".to_string()]), 2);
+    }
+}
@@ -0,0 +1,405 @@
+use std::fmt;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::Error;
+
+use dice::error::Span;
+use dice::parser::{parse_condition, parse_faces};
+
+/// The arithmetic operators supported between two expressions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl BinOp {
+    pub fn symbol(&self) -> &'static str {
+        match *self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+        }
+    }
+}
+
+/// Which end of a group of dice a modifier applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Selection {
+    Highest,
+    Lowest,
+}
+
+impl Selection {
+    /// The other end of the group
+    pub fn opposite(&self) -> Selection {
+        match *self {
+            Selection::Highest => Selection::Lowest,
+            Selection::Lowest => Selection::Highest,
+        }
+    }
+}
+
+/// How a face or a roll is compared against a target number
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CompareOp {
+    Equal,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+}
+
+impl CompareOp {
+    pub fn symbol(&self) -> &'static str {
+        match *self {
+            CompareOp::Equal => "=",
+            CompareOp::Greater => ">",
+            CompareOp::GreaterEqual => ">=",
+            CompareOp::Less => "<",
+            CompareOp::LessEqual => "<=",
+        }
+    }
+}
+
+/// A condition on a die's face, like the `>8` in `1d10!>8`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compare {
+    pub op: CompareOp,
+    pub target: i32,
+}
+
+impl Compare {
+    /// Whether a face meets the condition
+    pub fn matches(&self, face: i32) -> bool {
+        match self.op {
+            CompareOp::Equal => face == self.target,
+            CompareOp::Greater => face > self.target,
+            CompareOp::GreaterEqual => face >= self.target,
+            CompareOp::Less => face < self.target,
+            CompareOp::LessEqual => face <= self.target,
+        }
+    }
+}
+
+/// Conditions in the settings are written the way they are in expressions, like
+/// `">=19"`, or as a single face, like `"20"`.
+impl Serialize for Compare {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&FaceCondition(*self).to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Compare {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Compare, D::Error> {
+        let notation = String::deserialize(deserializer)?;
+        parse_condition(&notation).map_err(|e| D::Error::custom(format!("invalid condition `{}`: {}", notation, e.kind)))
+    }
+}
+
+/// The ways a die can explode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Explosion {
+    /// Each explosion rolls another die, which is added to the group: `1d6!`
+    Standard,
+    /// Each explosion is added to the die that exploded: `1d6!!`
+    Compounding,
+    /// Like standard explosions, but each extra die counts one less: `1d6!p`
+    Penetrating,
+}
+
+/// How many times a die may be rerolled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reroll {
+    /// Reroll the die once, keeping the new face whatever it is: `2d6ro<2`
+    Once,
+    /// Keep rerolling the die for as long as it meets the condition: `1d10r1`
+    Always,
+}
+
+/// Something that changes how a group of dice is rolled or counted
+#[derive(Debug, Clone, PartialEq)]
+pub enum Modifier {
+    /// Keep only this many of the highest or lowest dice, like `2d20kh1`
+    Keep(Selection, u32),
+    /// Drop this many of the highest or lowest dice, like `4d6dl1`
+    Drop(Selection, u32),
+    /// Roll again when a die meets the condition, or shows its highest face if
+    /// there is no condition
+    Explode(Explosion, Option<Compare>),
+    /// Roll a die again when it meets the condition
+    Reroll(Reroll, Compare),
+    /// Count the dice that meet the condition as successes instead of adding them
+    /// up, like `8d10>=8`
+    Success(Compare),
+    /// Count the dice that meet the condition as failures, which cancel out
+    /// successes, like the `f1` in `8d10>=8f1`
+    Failure(Compare),
+}
+
+/// One face of a custom die
+#[derive(Debug, Clone, PartialEq)]
+pub struct Face {
+    /// What the face counts as
+    pub value: i32,
+    /// How the face is shown, if not as its value, like the `hit` in `d{miss=0,hit=1}`
+    pub label: Option<String>,
+}
+
+/// The faces of a kind of die
+#[derive(Debug, Clone, PartialEq)]
+pub enum Faces {
+    /// Faces numbered from 1 up to this many, like the `6` in `3d6`
+    Numbered(u32),
+    /// Fudge dice, with two each of -1, 0 and +1: `4dF`
+    Fudge,
+    /// Any list of faces, each equally likely: `d{1,1,2,3,5,8}`
+    Custom(Vec<Face>),
+    /// Dice defined in the settings, like `@hit`. They have no faces of their own;
+    /// the evaluator looks up the faces of the dice with this name.
+    Named(String),
+}
+
+impl Faces {
+    /// How many faces the die has
+    pub fn sides(&self) -> u32 {
+        match *self {
+            Faces::Numbered(sides) => sides,
+            Faces::Fudge => 3,
+            Faces::Custom(ref faces) => faces.len() as u32,
+            Faces::Named(_) => 0,
+        }
+    }
+
+    /// The lowest face
+    pub fn lowest(&self) -> i32 {
+        match *self {
+            Faces::Numbered(_) => 1,
+            Faces::Fudge => -1,
+            Faces::Custom(ref faces) => faces.iter().map(|face| face.value).min().unwrap_or(0),
+            Faces::Named(_) => 0,
+        }
+    }
+
+    /// The highest face
+    pub fn highest(&self) -> i32 {
+        match *self {
+            Faces::Numbered(sides) => sides as i32,
+            Faces::Fudge => 1,
+            Faces::Custom(ref faces) => faces.iter().map(|face| face.value).max().unwrap_or(0),
+            Faces::Named(_) => 0,
+        }
+    }
+
+    /// What the face at a position among the die's faces counts as, counting from 0.
+    /// Fudge dice run from -1 up to +1.
+    pub fn value(&self, index: usize) -> i32 {
+        match *self {
+            Faces::Numbered(_) => index as i32 + 1,
+            Faces::Fudge => index as i32 - 1,
+            Faces::Custom(ref faces) => faces[index].value,
+            Faces::Named(_) => 0,
+        }
+    }
+
+    /// The position of the first face that counts as `value`, if any
+    pub fn position(&self, value: i32) -> Option<usize> {
+        (0..self.sides() as usize).find(|&index| self.value(index) == value)
+    }
+
+    /// How the face at a position among the die's faces is shown, if not as a number.
+    /// Fudge dice show `+`, `-` or `␣`, and custom dice show the face's own label, so
+    /// faces that count the same can still be told apart.
+    pub fn label(&self, index: usize) -> Option<String> {
+        match *self {
+            Faces::Numbered(_) | Faces::Named(_) => None,
+            Faces::Fudge => Some(match index {
+                2 => "+",
+                0 => "-",
+                _ => "\u{2423}",
+            }.to_string()),
+            Faces::Custom(ref faces) => faces.get(index).and_then(|face| face.label.clone()),
+        }
+    }
+}
+
+/// Dice in the settings are written the way they are in expressions, like
+/// `"d{1,1,2,3,5,8}"`.
+impl Serialize for Faces {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("d{}", self))
+    }
+}
+
+impl<'de> Deserialize<'de> for Faces {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Faces, D::Error> {
+        let notation = String::deserialize(deserializer)?;
+        parse_faces(&notation).map_err(|e| D::Error::custom(format!("invalid dice `{}`: {}", notation, e.kind)))
+    }
+}
+
+/// A group of identical dice, such as `3d6` or `4d6dl1`
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiceExpr {
+    /// How many dice to roll
+    pub count: u32,
+    /// The faces of each die
+    pub faces: Faces,
+    /// The modifiers applied to the group, in the order they were written
+    pub modifiers: Vec<Modifier>,
+    pub span: Span,
+}
+
+/// A parsed dice expression
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    /// A constant, like the `2` in `1d20 + 2`
+    Number { value: i32, span: Span },
+    /// A group of dice
+    Dice(DiceExpr),
+    /// A negated expression, like `-1d4`
+    Negate { operand: Box<Expr>, span: Span },
+    /// Two expressions joined by an operator
+    Binary { op: BinOp, lhs: Box<Expr>, rhs: Box<Expr>, span: Span },
+    /// A parenthesized expression. Kept so the expression can be printed as written.
+    Group { inner: Box<Expr>, span: Span },
+    /// A name on its own, like `@str_mod`. If dice have that name, it rolls one of
+    /// them; otherwise it's the value of the variable with that name.
+    Variable { name: String, span: Span },
+    /// An expression tagged with a kind, like the damage type in `2d6[fire]`
+    Tagged { inner: Box<Expr>, tag: String, span: Span },
+    /// A roll checked against a target, like `1d20 + 5 >= 15`. Only ever found at the
+    /// top of an expression.
+    Check { roll: Box<Expr>, op: CompareOp, target: Box<Expr>, span: Span },
+}
+
+impl Expr {
+    /// The part of the input this expression was parsed from
+    pub fn span(&self) -> Span {
+        match *self {
+            Expr::Number { span, .. } => span,
+            Expr::Dice(ref dice) => dice.span,
+            Expr::Negate { span, .. } => span,
+            Expr::Binary { span, .. } => span,
+            Expr::Group { span, .. } => span,
+            Expr::Variable { span, .. } => span,
+            Expr::Tagged { span, .. } => span,
+            Expr::Check { span, .. } => span,
+        }
+    }
+}
+
+impl fmt::Display for Compare {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", self.op.symbol(), self.target)
+    }
+}
+
+impl fmt::Display for Modifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (action, selection, count) = match *self {
+            Modifier::Keep(selection, count) => ("k", selection, count),
+            Modifier::Drop(selection, count) => ("d", selection, count),
+            Modifier::Explode(explosion, compare) => {
+                match explosion {
+                    Explosion::Standard => write!(f, "!")?,
+                    Explosion::Compounding => write!(f, "!!")?,
+                    Explosion::Penetrating => write!(f, "!p")?,
+                }
+                if let Some(compare) = compare {
+                    write!(f, "{}", compare)?;
+                }
+                return Ok(());
+            },
+            Modifier::Reroll(reroll, compare) => {
+                return match reroll {
+                    Reroll::Once => write!(f, "ro{}", FaceCondition(compare)),
+                    Reroll::Always => write!(f, "r{}", FaceCondition(compare)),
+                };
+            },
+            Modifier::Success(compare) => return write!(f, "{}", compare),
+            Modifier::Failure(compare) => return write!(f, "f{}", FaceCondition(compare)),
+        };
+        let end = match selection {
+            Selection::Highest => "h",
+            Selection::Lowest => "l",
+        };
+        write!(f, "{}{}{}", action, end, count)
+    }
+}
+
+/// Prints a condition that may also be written as a single face. A single face is
+/// written without the `=`, as in `1d10r1`.
+struct FaceCondition(Compare);
+
+impl fmt::Display for FaceCondition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0.op {
+            CompareOp::Equal => write!(f, "{}", self.0.target),
+            _ => write!(f, "{}", self.0),
+        }
+    }
+}
+
+impl fmt::Display for Face {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.label {
+            Some(ref label) => write!(f, "{}={}", label, self.value),
+            None => write!(f, "{}", self.value),
+        }
+    }
+}
+
+/// Prints what follows the `d`, like the `6` in `3d6` or the `{1,2,3}` in `1d{1,2,3}`
+impl fmt::Display for Faces {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Faces::Numbered(sides) => write!(f, "{}", sides),
+            Faces::Fudge => write!(f, "F"),
+            Faces::Custom(ref faces) => {
+                write!(f, "{{")?;
+                for (i, face) in faces.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", face)?;
+                }
+                write!(f, "}}")
+            },
+            Faces::Named(ref name) => write!(f, "@{}", name),
+        }
+    }
+}
+
+impl fmt::Display for DiceExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.faces {
+            // Named dice are written without the `d`, like `3@hit`
+            Faces::Named(ref name) => write!(f, "{}@{}", self.count, name)?,
+            ref faces => write!(f, "{}d{}", self.count, faces)?,
+        }
+        for modifier in &self.modifiers {
+            write!(f, "{}", modifier)?;
+        }
+        Ok(())
+    }
+}
+
+/// Prints the expression in normalized infix form, e.g. `(2d20 + 4) / 2`
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Expr::Number { value, .. } => write!(f, "{}", value),
+            Expr::Dice(ref dice) => write!(f, "{}", dice),
+            Expr::Negate { ref operand, .. } => write!(f, "-{}", operand),
+            Expr::Binary { op, ref lhs, ref rhs, .. } => write!(f, "{} {} {}", lhs, op.symbol(), rhs),
+            Expr::Group { ref inner, .. } => write!(f, "({})", inner),
+            Expr::Variable { ref name, .. } => write!(f, "@{}", name),
+            Expr::Tagged { ref inner, ref tag, .. } => write!(f, "{}[{}]", inner, tag),
+            Expr::Check { ref roll, op, ref target, .. } => write!(f, "{} {} {}", roll, op.symbol(), target),
+        }
+    }
+}
@@ -0,0 +1,284 @@
+use std::fmt;
+
+use dice::ast::{BinOp, CompareOp};
+
+/// A single die and the face it landed on
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DieRoll {
+    /// The face the die landed on
+    pub value: i32,
+    /// Whether the die counts towards the total
+    pub kept: bool,
+    /// Whether the die exploded, rolling another die
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub exploded: bool,
+    /// For a compounding die, every face it showed, which add up to its value
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chain: Vec<i32>,
+    /// The faces the die was rerolled from, in the order they were rolled
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rerolled: Vec<i32>,
+    /// Whether the die counts as a success in a pool
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub success: bool,
+    /// Whether the die counts as a failure in a pool
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub failure: bool,
+    /// Which of its die's faces the die landed on, counting from 0, for dice whose
+    /// faces are shown by label, since several faces may count the same
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub face: Option<usize>,
+    /// How the face is shown, if not as a number, like the `+` of a Fudge die
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Whether the face is a critical hit
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub critical: bool,
+    /// Whether the face is a fumble
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub fumble: bool,
+}
+
+impl DieRoll {
+    /// A kept die showing `value`
+    pub fn new(value: i32) -> DieRoll {
+        DieRoll {
+            value,
+            kept: true,
+            exploded: false,
+            chain: Vec::new(),
+            rerolled: Vec::new(),
+            success: false,
+            failure: false,
+            face: None,
+            label: None,
+            critical: false,
+            fumble: false,
+        }
+    }
+}
+
+/// Whether a flag is unset, so it can be left out when serializing
+pub(crate) fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+/// A group of dice and every face they rolled
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DiceRoll {
+    /// The dice as written in the normalized expression, e.g. `3d6`
+    pub notation: String,
+    /// How many sides each die has
+    pub sides: u32,
+    /// Each die in the group, in the order it was rolled
+    pub dice: Vec<DieRoll>,
+    /// Whether the group counts successes rather than adding up its faces
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub pool: bool,
+    /// Whether the group is of Fudge dice, like `4dF`
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub fudge: bool,
+}
+
+impl DiceRoll {
+    /// The sum of all the kept dice, or for a pool, the kept successes less the kept
+    /// failures
+    pub fn total(&self) -> i32 {
+        if self.pool {
+            self.successes() as i32 - self.failures() as i32
+        } else {
+            self.dice.iter().filter(|d| d.kept).map(|d| d.value).sum()
+        }
+    }
+
+    /// How many kept dice count as successes
+    pub fn successes(&self) -> u32 {
+        self.dice.iter().filter(|d| d.kept && d.success).count() as u32
+    }
+
+    /// How many kept dice count as failures
+    pub fn failures(&self) -> u32 {
+        self.dice.iter().filter(|d| d.kept && d.failure).count() as u32
+    }
+}
+
+/// The successes counted by every pool in an expression
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PoolResult {
+    /// How many dice counted as successes
+    pub successes: u32,
+    /// How many dice counted as failures
+    pub failures: u32,
+    /// Whether there were failures but no successes at all
+    pub botch: bool,
+    /// Whether the successes left after failures reached the exceptional threshold
+    pub exceptional: bool,
+}
+
+/// How a roll fared against the target it was checked against
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CheckResult {
+    /// How the roll was compared against the target
+    pub op: CompareOp,
+    /// The target, like the DC of `1d20 + 5 >= 15`
+    pub target: i32,
+    /// Whether the roll met the target
+    pub passed: bool,
+    /// How far the roll was past the target, in the direction it needed to go, so
+    /// it's negative for rolls that fell short
+    pub margin: i32,
+}
+
+/// How much of a roll's value was tagged with one tag, like the `7 fire` of
+/// `1d8[slashing] + 2d6[fire]`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Subtotal {
+    pub tag: String,
+    pub total: i32,
+}
+
+/// One piece of an evaluated expression, in the order it was written
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Term {
+    /// A group of dice and the faces they rolled
+    Dice(DiceRoll),
+    /// A constant modifier
+    Constant(i32),
+    /// An operator between the terms either side of it
+    Operator(BinOp),
+    /// A minus sign negating the term after it
+    Negate,
+    OpenParen,
+    CloseParen,
+    /// The comparison between a roll and the target it's checked against
+    Compare(CompareOp),
+    /// The tag of the term before it, like the `fire` of `2d6[fire]`
+    Tag(String),
+}
+
+/// Prints the face, marking exploded dice like `6!`, showing what a compounding die
+/// added up, like `15 (6+6+3)` or `0 (1+1-2)`, and leading with any faces it was rerolled from, like
+/// `1→4`. Faces with a label, like those of Fudge dice, are shown by it. In a pool,
+/// successes are marked like `9✓` and failures like `1✗`.
+impl fmt::Display for DieRoll {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut face = match self.label {
+            Some(ref label) => label.clone(),
+            None => self.value.to_string(),
+        };
+        if !self.chain.is_empty() {
+            let mut faces = self.chain[0].to_string();
+            for &added in &self.chain[1..] {
+                if added < 0 {
+                    faces.push_str(&format!("-{}", -i64::from(added)));
+                } else {
+                    faces.push_str(&format!("+{}", added));
+                }
+            }
+            face = format!("{} ({})", face, faces);
+        } else if self.exploded {
+            face.push('!');
+        }
+        if self.success {
+            face.push('✓');
+        }
+        if self.failure {
+            face.push('✗');
+        }
+        for replaced in self.rerolled.iter().rev() {
+            face = format!("{}→{}", replaced, face);
+        }
+        if self.kept {
+            write!(f, "{}", face)
+        } else {
+            // Dropped dice are struck out
+            write!(f, "~{}~", face)
+        }
+    }
+}
+
+/// Prints the dice followed by their faces, like `3d6 [6, 1, 4]`
+impl fmt::Display for DiceRoll {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} [", self.notation)?;
+        for (i, die) in self.dice.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", die)?;
+        }
+        write!(f, "]")
+    }
+}
+
+impl fmt::Display for Term {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Term::Dice(ref dice) => write!(f, "{}", dice),
+            Term::Constant(value) => write!(f, "{}", value),
+            Term::Operator(op) => write!(f, "{}", op.symbol()),
+            Term::Negate => write!(f, "-"),
+            Term::OpenParen => write!(f, "("),
+            Term::CloseParen => write!(f, ")"),
+            Term::Compare(op) => write!(f, "{}", op.symbol()),
+            Term::Tag(ref tag) => write!(f, "{}", tag),
+        }
+    }
+}
+
+/// Prints the subtotal like `7 fire`
+impl fmt::Display for Subtotal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.total, self.tag)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dropped_dice_are_struck_out() {
+        assert_eq!(DieRoll { kept: false, ..DieRoll::new(1) }.to_string(), "~1~");
+    }
+
+    #[test]
+    fn exploded_dice_are_marked() {
+        assert_eq!(DieRoll { exploded: true, ..DieRoll::new(6) }.to_string(), "6!");
+        assert_eq!(DieRoll { chain: vec![6, 6, 3], ..DieRoll::new(15) }.to_string(), "15 (6+6+3)");
+    }
+
+    #[test]
+    fn rerolled_dice_lead_with_their_old_faces() {
+        assert_eq!(DieRoll { rerolled: vec![1, 2], ..DieRoll::new(4) }.to_string(), "1→2→4");
+    }
+
+    #[test]
+    fn successes_and_failures_are_marked() {
+        assert_eq!(DieRoll { success: true, ..DieRoll::new(9) }.to_string(), "9✓");
+        assert_eq!(DieRoll { failure: true, ..DieRoll::new(1) }.to_string(), "1✗");
+    }
+
+    #[test]
+    fn compounded_chains_show_their_signs() {
+        let fudge = DieRoll { chain: vec![1, -1], label: Some("\u{2423}".to_string()), ..DieRoll::new(0) };
+        assert_eq!(fudge.to_string(), "\u{2423} (1-1)");
+    }
+
+    #[test]
+    fn pools_total_their_successes() {
+        let dice = DiceRoll {
+            notation: "3d10>=8f1".to_string(),
+            sides: 10,
+            dice: vec![
+                DieRoll { success: true, ..DieRoll::new(9) },
+                DieRoll { failure: true, ..DieRoll::new(1) },
+                DieRoll { success: true, kept: false, ..DieRoll::new(8) },
+            ],
+            pool: true,
+            fudge: false,
+        };
+        assert_eq!((dice.successes(), dice.failures(), dice.total()), (1, 1, 0));
+        assert_eq!(dice.to_string(), "3d10>=8f1 [9✓, 1✗, ~8✓~]");
+    }
+}
@@ -0,0 +1,102 @@
+use std::error::Error;
+use std::fmt;
+
+/// A range of bytes in the input string, used to point at the part of an
+/// expression that caused a problem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    /// The byte offset of the first character covered by the span
+    pub start: usize,
+    /// The byte offset just past the last character covered by the span
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+
+    /// The smallest span covering both this span and the other.
+    pub fn to(self, other: Span) -> Span {
+        Span::new(self.start.min(other.start), self.end.max(other.end))
+    }
+}
+
+/// What went wrong while parsing or evaluating an expression.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+    /// Something other than what the grammar allows at this point.
+    /// `found` is `None` when the input ended too early.
+    UnexpectedToken { found: Option<String>, expected: &'static str },
+    /// A parenthesis without a partner
+    UnbalancedParens,
+    /// The right hand side of a division evaluated to zero
+    DivisionByZero,
+    /// A number or intermediate result didn't fit in the supported range
+    Overflow,
+    /// There was nothing to roll
+    EmptyInput,
+    /// Dice that would be rerolled whatever they showed, like `1d6r<7`
+    EndlessReroll,
+    /// Dice rerolled more times altogether than the limit allows, like `1d1000r<1000`
+    TooManyRerolls(u32),
+    /// A pool with a second condition for successes or failures, like `2d6>=3>=3`
+    RepeatedCondition,
+    /// A condition after a space following several dice, like `2d6 >= 7`, which could
+    /// either count the dice meeting it or check their total
+    AmbiguousCondition,
+    /// Named dice, like `@hit`, that haven't been defined
+    UnknownDice(String),
+    /// A name, like `@str_mod`, that's neither dice nor a variable
+    UnknownVariable(String),
+    /// A macro, like `#longsword`, that hasn't been defined
+    UnknownMacro(String),
+    /// A roll repeated too few or too many times, like `0x 1d20`
+    InvalidRepeat,
+}
+
+/// An error produced while rolling an expression, carrying the span of the input
+/// responsible for it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RollError {
+    pub kind: ErrorKind,
+    pub span: Span,
+}
+
+impl RollError {
+    pub fn new(kind: ErrorKind, span: Span) -> RollError {
+        RollError { kind, span }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ErrorKind::UnexpectedToken { found: Some(ref found), expected } =>
+                write!(f, "expected {}, found `{}`", expected, found),
+            ErrorKind::UnexpectedToken { found: None, expected } =>
+                write!(f, "expected {}, found end of input", expected),
+            ErrorKind::UnbalancedParens => write!(f, "unbalanced parentheses"),
+            ErrorKind::DivisionByZero => write!(f, "division by zero"),
+            ErrorKind::Overflow => write!(f, "number too large"),
+            ErrorKind::EmptyInput => write!(f, "nothing to roll"),
+            ErrorKind::EndlessReroll => write!(f, "every face would be rerolled"),
+            ErrorKind::TooManyRerolls(limit) => write!(f, "the dice would be rerolled more than {} times", limit),
+            ErrorKind::RepeatedCondition => write!(f, "a pool can only have one condition for successes and one for failures"),
+            ErrorKind::AmbiguousCondition => write!(f, "write the condition straight after the dice, like `2d6>=4`, to count \
+                the dice meeting it, or put the dice in parentheses, like `(2d6) >= 7`, to check their total"),
+            ErrorKind::UnknownDice(ref name) => write!(f, "no dice named `@{}`", name),
+            ErrorKind::UnknownVariable(ref name) => write!(f, "no dice or variable named `@{}`", name),
+            ErrorKind::UnknownMacro(ref name) => write!(f, "no macro named `#{}`", name),
+            ErrorKind::InvalidRepeat => write!(f, "a roll can be repeated 1 to {} times", super::MAX_REPEAT),
+        }
+    }
+}
+
+impl fmt::Display for RollError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (at {}..{})", self.kind, self.span.start, self.span.end)
+    }
+}
+
+impl Error for RollError {}
@@ -0,0 +1,886 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+use rand::Rng;
+
+use dice::ast::{BinOp, Compare, CompareOp, DiceExpr, Explosion, Expr, Faces, Modifier, Reroll, Selection};
+use dice::breakdown::{CheckResult, DiceRoll, DieRoll, PoolResult, Subtotal, Term};
+use dice::error::{ErrorKind, RollError, Span};
+
+/// The most dice a single group may roll, so a typo can't hang the program.
+pub const MAX_DICE: u32 = 10_000;
+
+/// The most times a single roll may be repeated, like the `6` in `6x 4d6dl1`
+pub const MAX_REPEAT: u32 = 100;
+
+/// Settings that change how an expression is evaluated
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvalOptions {
+    /// The most times a single die may explode, so a die that always explodes
+    /// can't roll forever
+    pub explosion_limit: u32,
+    /// The most times the dice of a single roll may be rerolled altogether, so dice
+    /// that almost always reroll, like `1d1000r<1000`, can't hang the program
+    pub reroll_limit: u32,
+    /// How many successes a pool needs, after failures, to be exceptional
+    pub exceptional_successes: u32,
+    /// The dice that can be rolled by name, like `@hit`, without the `@`
+    pub dice: BTreeMap<String, Faces>,
+    /// Which faces are critical hits and fumbles, by the number of sides of the die
+    pub crits: BTreeMap<u32, CritRule>,
+    /// The expressions that can be rolled by name, like `#longsword`, without the `#`
+    pub macros: BTreeMap<String, String>,
+    /// The values of variables, like `@str_mod`, without the `@`. Dice with the same
+    /// name take priority.
+    pub variables: BTreeMap<String, i32>,
+    /// How the dice are counted
+    pub mode: Mode,
+    /// How the dice of a critical hit are rolled
+    pub crit_damage: CritDamage,
+}
+
+impl Default for EvalOptions {
+    fn default() -> EvalOptions {
+        EvalOptions {
+            explosion_limit: 100,
+            reroll_limit: 100_000,
+            exceptional_successes: 5,
+            dice: BTreeMap::new(),
+            crits: default_crits(),
+            macros: BTreeMap::new(),
+            variables: BTreeMap::new(),
+            mode: Mode::Normal,
+            crit_damage: CritDamage::DoubleDice,
+        }
+    }
+}
+
+/// How the dice of an expression are counted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// The dice are rolled
+    #[default]
+    Normal,
+    /// Each group of dice counts its average, rounded down, like the `7 (2d6)` of a
+    /// stat block
+    Average,
+    /// Each die shows its highest face
+    Maximize,
+    /// The dice are rolled for a critical hit, as `CritDamage` says
+    Critical,
+}
+
+impl Mode {
+    /// Every mode, in the order they're offered
+    pub fn all() -> &'static [Mode] {
+        &[Mode::Normal, Mode::Average, Mode::Maximize, Mode::Critical]
+    }
+
+    /// The name shown to users, like `Average`
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Normal => "Normal",
+            Mode::Average => "Average",
+            Mode::Maximize => "Maximize",
+            Mode::Critical => "Critical",
+        }
+    }
+
+    /// The word written after an expression to roll it in this mode, like the `crit` in
+    /// `2d6 + 3 crit`, if there is one
+    pub fn suffix(self) -> Option<&'static str> {
+        match self {
+            Mode::Normal => None,
+            Mode::Average => Some("avg"),
+            Mode::Maximize => Some("max"),
+            Mode::Critical => Some("crit"),
+        }
+    }
+
+    /// The mode written with a suffix, like `crit`
+    pub fn from_suffix(suffix: &str) -> Option<Mode> {
+        Mode::all().iter().cloned().find(|mode| mode.suffix() == Some(suffix))
+    }
+
+    /// Whether the dice show set faces instead of being rolled
+    fn is_fixed(self) -> bool {
+        self == Mode::Average || self == Mode::Maximize
+    }
+}
+
+/// How the dice of a critical hit are rolled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CritDamage {
+    /// Roll twice as many dice, so `2d6` rolls `4d6`
+    #[default]
+    DoubleDice,
+    /// Count the most the dice could roll, then roll them as usual and add that on
+    MaxPlusRoll,
+}
+
+/// Which faces of a die are critical hits and which are fumbles
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CritRule {
+    /// The faces that are critical hits, like `20` or `>=19`
+    pub critical: Option<Compare>,
+    /// The faces that are fumbles, like `1`
+    pub fumble: Option<Compare>,
+}
+
+/// A natural 20 on a d20 is a critical hit, and a natural 1 a fumble.
+pub fn default_crits() -> BTreeMap<u32, CritRule> {
+    let mut crits = BTreeMap::new();
+    crits.insert(20, CritRule {
+        critical: Some(Compare { op: CompareOp::Equal, target: 20 }),
+        fumble: Some(Compare { op: CompareOp::Equal, target: 1 }),
+    });
+    crits
+}
+
+/// The result of evaluating an expression
+#[derive(Debug, Clone, PartialEq)]
+pub struct Evaluation {
+    /// The final value of the expression
+    pub value: i32,
+    /// Every term of the expression, with the faces each dice group rolled
+    pub terms: Vec<Term>,
+    /// The successes counted, if the expression rolled any pools
+    pub pool: Option<PoolResult>,
+    /// How the roll fared, if it was checked against a target
+    pub check: Option<CheckResult>,
+    /// Whether any kept die rolled a critical hit
+    pub critical: bool,
+    /// Whether any kept die rolled a fumble
+    pub fumble: bool,
+    /// How much of the value each tag accounts for, in the order the tags were first
+    /// written, like `5 slashing` and `7 fire` for `1d8[slashing] + 2d6[fire]`
+    pub subtotals: Vec<Subtotal>,
+}
+
+/// Roll all the dice in an expression and compute its value.
+pub fn evaluate<R: Rng + ?Sized>(expr: &Expr, rng: &mut R, options: &EvalOptions) -> Result<Evaluation, RollError> {
+    let mut evaluator = Evaluator { rng, options, terms: Vec::new(), check: None, rerolls: 0 };
+    let Value { total: value, subtotals } = evaluator.eval(expr)?;
+    let pool = pool_result(&evaluator.terms, options);
+    let kept = || evaluator.terms.iter()
+        .filter_map(|term| match *term {
+            Term::Dice(ref dice) => Some(dice.dice.iter().filter(|die| die.kept)),
+            _ => None,
+        })
+        .flatten();
+    let critical = kept().any(|die| die.critical);
+    let fumble = kept().any(|die| die.fumble);
+    Ok(Evaluation { value, terms: evaluator.terms, pool, check: evaluator.check, critical, fumble, subtotals })
+}
+
+/// Replace the names in an expression with what they stand for: dice names with a
+/// single die, like `1@hit`, and variables with their values. Dice take priority over
+/// variables, and names that are neither are left as they are.
+pub fn resolve(expr: &Expr, options: &EvalOptions) -> Expr {
+    let resolve = |expr: &Expr| Box::new(resolve(expr, options));
+    match *expr {
+        Expr::Variable { ref name, span } => {
+            if options.dice.contains_key(name) {
+                Expr::Dice(DiceExpr { count: 1, faces: Faces::Named(name.clone()), modifiers: Vec::new(), span })
+            } else if let Some(&value) = options.variables.get(name) {
+                Expr::Number { value, span }
+            } else {
+                expr.clone()
+            }
+        },
+        Expr::Negate { ref operand, span } => Expr::Negate { operand: resolve(operand), span },
+        Expr::Binary { op, ref lhs, ref rhs, span } => Expr::Binary { op, lhs: resolve(lhs), rhs: resolve(rhs), span },
+        Expr::Group { ref inner, span } => Expr::Group { inner: resolve(inner), span },
+        Expr::Tagged { ref inner, ref tag, span } => Expr::Tagged { inner: resolve(inner), tag: tag.clone(), span },
+        Expr::Check { ref roll, op, ref target, span } =>
+            Expr::Check { roll: resolve(roll), op, target: resolve(target), span },
+        Expr::Number { .. } | Expr::Dice(_) => expr.clone(),
+    }
+}
+
+/// Count up the successes of every pool among the terms.
+fn pool_result(terms: &[Term], options: &EvalOptions) -> Option<PoolResult> {
+    let pools: Vec<&DiceRoll> = terms.iter().filter_map(|term| match *term {
+        Term::Dice(ref dice) if dice.pool => Some(dice),
+        _ => None,
+    }).collect();
+    if pools.is_empty() {
+        return None;
+    }
+    let successes = pools.iter().map(|dice| dice.successes()).sum();
+    let failures = pools.iter().map(|dice| dice.failures()).sum();
+    Some(PoolResult {
+        successes,
+        failures,
+        botch: successes == 0 && failures > 0,
+        exceptional: successes.saturating_sub(failures) >= options.exceptional_successes,
+    })
+}
+
+/// The value of part of an expression, and how much of it each tag accounts for.
+///
+/// Tagged parts keep their share of the value through addition and subtraction, and
+/// through multiplication or division by an untagged value. The product or quotient
+/// of two tagged values can't be split between their tags, so it's left untagged.
+#[derive(Debug, Clone, Default)]
+struct Value {
+    total: i32,
+    /// The subtotal of each tag, in the order the tags were first written
+    subtotals: Vec<Subtotal>,
+}
+
+impl Value {
+    fn untagged(total: i32) -> Value {
+        Value { total, subtotals: Vec::new() }
+    }
+
+    /// The part of the total that no tag accounts for
+    fn rest(&self) -> Option<i32> {
+        self.subtotals.iter().try_fold(self.total, |rest, subtotal| rest.checked_sub(subtotal.total))
+    }
+
+    /// Add `total` to the subtotal of `tag`.
+    fn tag(&mut self, tag: &str, total: i32) -> Option<()> {
+        match self.subtotals.iter_mut().find(|subtotal| subtotal.tag == tag) {
+            Some(subtotal) => subtotal.total = subtotal.total.checked_add(total)?,
+            None => self.subtotals.push(Subtotal { tag: tag.to_string(), total }),
+        }
+        Some(())
+    }
+
+    /// The subtotals with `f` applied to each.
+    fn map<F: Fn(i32) -> Option<i32>>(&self, f: F) -> Option<Vec<Subtotal>> {
+        self.subtotals.iter()
+            .map(|subtotal| f(subtotal.total).map(|total| Subtotal { tag: subtotal.tag.clone(), total }))
+            .collect()
+    }
+}
+
+/// How the subtotals of two values combine under an operator.
+fn combine_subtotals(op: BinOp, left: &Value, right: &Value) -> Option<Vec<Subtotal>> {
+    match op {
+        BinOp::Add | BinOp::Sub => {
+            let mut value = left.clone();
+            for subtotal in &right.subtotals {
+                let total = if op == BinOp::Sub { subtotal.total.checked_neg()? } else { subtotal.total };
+                value.tag(&subtotal.tag, total)?;
+            }
+            Some(value.subtotals)
+        },
+        BinOp::Mul if right.subtotals.is_empty() => left.map(|total| total.checked_mul(right.total)),
+        BinOp::Mul if left.subtotals.is_empty() => right.map(|total| total.checked_mul(left.total)),
+        // Rounding each part down on its own can take off more than rounding the whole, so
+        // what it took off too much is given back to the largest subtotal
+        BinOp::Div if right.subtotals.is_empty() => {
+            let total = floor_div(left.total, right.total)?;
+            let rest = floor_div(left.rest()?, right.total)?;
+            let mut subtotals = left.map(|total| floor_div(total, right.total))?;
+            let remainder = subtotals.iter()
+                .try_fold(total.checked_sub(rest)?, |remainder, subtotal| remainder.checked_sub(subtotal.total))?;
+            // The first written of the largest, since `max_by_key` picks the last
+            if let Some(largest) = subtotals.iter_mut().rev().max_by_key(|subtotal| subtotal.total.abs()) {
+                largest.total = largest.total.checked_add(remainder)?;
+            }
+            Some(subtotals)
+        },
+        BinOp::Mul | BinOp::Div => Some(Vec::new()),
+    }
+}
+
+/// An evaluation in progress
+struct Evaluator<'a, R: Rng + ?Sized + 'a> {
+    rng: &'a mut R,
+    options: &'a EvalOptions,
+    /// The terms encountered so far
+    terms: Vec<Term>,
+    /// How the roll fared against its target, once it's been checked
+    check: Option<CheckResult>,
+    /// How many times dice have been rerolled so far
+    rerolls: u32,
+}
+
+impl<'a, R: Rng + ?Sized + 'a> Evaluator<'a, R> {
+    /// Evaluate an expression, appending its terms as they are encountered.
+    fn eval(&mut self, expr: &Expr) -> Result<Value, RollError> {
+        let overflow = || RollError::new(ErrorKind::Overflow, expr.span());
+        match *expr {
+            Expr::Number { value, .. } => {
+                self.terms.push(Term::Constant(value));
+                Ok(Value::untagged(value))
+            },
+            Expr::Dice(ref dice) => {
+                let roll = self.roll_dice(dice)?;
+                let total = roll.total();
+                self.terms.push(Term::Dice(roll));
+                Ok(Value::untagged(total))
+            },
+            Expr::Negate { ref operand, .. } => {
+                self.terms.push(Term::Negate);
+                let value = self.eval(operand)?;
+                Ok(Value {
+                    total: value.total.checked_neg().ok_or_else(overflow)?,
+                    subtotals: value.map(i32::checked_neg).ok_or_else(overflow)?,
+                })
+            },
+            Expr::Binary { op, ref lhs, ref rhs, .. } => {
+                let left = self.eval(lhs)?;
+                self.terms.push(Term::Operator(op));
+                let right = self.eval(rhs)?;
+                let total = match op {
+                    BinOp::Add => left.total.checked_add(right.total),
+                    BinOp::Sub => left.total.checked_sub(right.total),
+                    BinOp::Mul => left.total.checked_mul(right.total),
+                    BinOp::Div => {
+                        if right.total == 0 {
+                            return Err(RollError::new(ErrorKind::DivisionByZero, rhs.span()));
+                        }
+                        floor_div(left.total, right.total)
+                    },
+                };
+                Ok(Value {
+                    total: total.ok_or_else(overflow)?,
+                    subtotals: combine_subtotals(op, &left, &right).ok_or_else(overflow)?,
+                })
+            },
+            Expr::Group { ref inner, .. } => {
+                self.terms.push(Term::OpenParen);
+                let value = self.eval(inner)?;
+                self.terms.push(Term::CloseParen);
+                Ok(value)
+            },
+            Expr::Variable { ref name, span } => match resolve(expr, self.options) {
+                Expr::Variable { .. } => Err(RollError::new(ErrorKind::UnknownVariable(name.clone()), span)),
+                resolved => self.eval(&resolved),
+            },
+            // The tag accounts for whatever tags inside it don't
+            Expr::Tagged { ref inner, ref tag, .. } => {
+                let mut value = self.eval(inner)?;
+                self.terms.push(Term::Tag(tag.clone()));
+                let rest = value.rest().ok_or_else(overflow)?;
+                if rest != 0 || value.subtotals.is_empty() {
+                    value.tag(tag, rest).ok_or_else(overflow)?;
+                }
+                Ok(value)
+            },
+            // The value of a check is the value of the roll
+            Expr::Check { ref roll, op, ref target, .. } => {
+                let value = self.eval(roll)?;
+                self.terms.push(Term::Compare(op));
+                let target = self.eval(target)?.total;
+                let margin = match op {
+                    CompareOp::Less | CompareOp::LessEqual => target.checked_sub(value.total),
+                    _ => value.total.checked_sub(target),
+                }.ok_or_else(overflow)?;
+                let passed = Compare { op, target }.matches(value.total);
+                self.check = Some(CheckResult { op, target, passed, margin });
+                Ok(value)
+            },
+        }
+    }
+
+    fn roll_face(&mut self, faces: &Faces) -> DieRoll {
+        match *faces {
+            Faces::Numbered(sides) => DieRoll::new(self.rng.gen_range(1, sides + 1) as i32),
+            Faces::Fudge => landed_on(faces, (self.rng.gen_range(-1, 2) + 1) as usize),
+            Faces::Custom(ref custom) => landed_on(faces, self.rng.gen_range(0, custom.len())),
+            Faces::Named(_) => unreachable!("named dice are looked up before rolling"),
+        }
+    }
+
+    /// Roll a single die, rerolling it as the rerolls say. The die records the faces
+    /// it was rerolled from, in the order they were rolled. Fails once the roll has
+    /// rerolled more dice than the options allow.
+    fn roll_die(&mut self, faces: &Faces, rerolls: &[(Reroll, Compare)], span: Span) -> Result<DieRoll, RollError> {
+        let mut die = self.roll_face(faces);
+        let mut replaced = Vec::new();
+        while rerolls.iter().any(|&(reroll, on)| on.matches(die.value) && (reroll == Reroll::Always || replaced.is_empty())) {
+            if self.rerolls >= self.options.reroll_limit {
+                return Err(RollError::new(ErrorKind::TooManyRerolls(self.options.reroll_limit), span));
+            }
+            self.rerolls += 1;
+            replaced.push(die.value);
+            die = self.roll_face(faces);
+        }
+        die.rerolled = replaced;
+        Ok(die)
+    }
+
+    /// The faces of some dice, looking them up if they're named.
+    fn faces<'d>(&self, dice: &'d DiceExpr) -> Result<&'d Faces, RollError>
+        where 'a: 'd
+    {
+        match dice.faces {
+            Faces::Named(ref name) => self.options.dice.get(name)
+                .ok_or_else(|| RollError::new(ErrorKind::UnknownDice(name.clone()), dice.span)),
+            ref faces => Ok(faces),
+        }
+    }
+
+    fn roll_dice(&mut self, dice: &DiceExpr) -> Result<DiceRoll, RollError> {
+        let faces = self.faces(dice)?.clone();
+        let mode = self.options.mode;
+        let count = match (mode, self.options.crit_damage) {
+            (Mode::Critical, CritDamage::DoubleDice) => dice.count.saturating_mul(2),
+            _ => dice.count,
+        };
+        if count > MAX_DICE || faces.sides() > i32::MAX as u32 {
+            return Err(RollError::new(ErrorKind::Overflow, dice.span));
+        }
+        let overflow = || RollError::new(ErrorKind::Overflow, dice.span);
+
+        // Without a condition, dice explode on their highest face
+        let explosion = dice.modifiers.iter().filter_map(|modifier| match *modifier {
+            Modifier::Explode(explosion, on) => {
+                Some((explosion, on.unwrap_or(Compare { op: CompareOp::Equal, target: faces.highest() })))
+            },
+            _ => None,
+        }).next().filter(|_| !mode.is_fixed());
+        let limit = self.options.explosion_limit;
+
+        // Dice that aren't rolled are never rerolled either
+        let rerolls: Vec<(Reroll, Compare)> = dice.modifiers.iter().filter_map(|modifier| match *modifier {
+            Modifier::Reroll(reroll, on) if !mode.is_fixed() => Some((reroll, on)),
+            _ => None,
+        }).collect();
+        if rerolls_every_face(&rerolls, &faces) {
+            return Err(RollError::new(ErrorKind::EndlessReroll, dice.span));
+        }
+
+        // The dice showing set faces come first, and are never critical hits or fumbles
+        let mut rolled = match mode {
+            _ if mode.is_fixed() => fixed_dice(&faces, count, mode).ok_or_else(overflow)?,
+            Mode::Critical if self.options.crit_damage == CritDamage::MaxPlusRoll =>
+                fixed_dice(&faces, count, Mode::Maximize).ok_or_else(overflow)?,
+            _ => Vec::with_capacity(count as usize),
+        };
+        let fixed = rolled.len();
+        for _ in 0..if mode.is_fixed() { 0 } else { count } {
+            let mut die = self.roll_die(&faces, &rerolls, dice.span)?;
+            match explosion {
+                None => rolled.push(die),
+                Some((Explosion::Compounding, on)) => {
+                    // Every explosion is added onto the same die
+                    let mut chain = vec![die.value];
+                    while on.matches(*chain.last().unwrap()) && (chain.len() as u32) <= limit {
+                        let next = self.roll_die(&faces, &rerolls, dice.span)?;
+                        chain.push(next.value);
+                        die.rerolled.extend(next.rerolled);
+                    }
+                    if chain.len() > 1 {
+                        // The die shows the sum of its faces, which has no label
+                        die.value = chain.iter().try_fold(0i32, |sum, &face| sum.checked_add(face)).ok_or_else(overflow)?;
+                        die.face = None;
+                        die.label = None;
+                        die.exploded = true;
+                        die.chain = chain;
+                    }
+                    rolled.push(die);
+                },
+                Some((explosion, on)) => {
+                    let mut explosions = 0;
+                    loop {
+                        // Penetrating dice count one less than they show, but explode
+                        // on what they show
+                        let face = die.value;
+                        if explosion == Explosion::Penetrating && explosions > 0 {
+                            die.value -= 1;
+                            die.label = None;
+                        }
+                        die.exploded = on.matches(face) && explosions < limit;
+                        let exploded = die.exploded;
+                        rolled.push(die);
+                        if !exploded {
+                            break;
+                        }
+                        explosions += 1;
+                        die = self.roll_die(&faces, &rerolls, dice.span)?;
+                    }
+                },
+            }
+        }
+        // Make sure the group can be totalled whichever dice end up kept
+        rolled.iter().try_fold(0i32, |sum, die| sum.checked_add(die.value)).ok_or_else(overflow)?;
+
+        let mut pool = false;
+        for modifier in &dice.modifiers {
+            match *modifier {
+                Modifier::Keep(selection, count) => {
+                    let kept = rolled.iter().filter(|die| die.kept).count();
+                    drop_dice(&mut rolled, selection.opposite(), kept.saturating_sub(count as usize));
+                },
+                Modifier::Drop(selection, count) => drop_dice(&mut rolled, selection, count as usize),
+                Modifier::Success(on) => {
+                    for die in &mut rolled {
+                        die.success |= on.matches(die.value);
+                    }
+                    pool = true;
+                },
+                Modifier::Failure(on) => {
+                    for die in &mut rolled {
+                        die.failure |= on.matches(die.value);
+                    }
+                    pool = true;
+                },
+                Modifier::Explode(..) | Modifier::Reroll(..) => {},
+            }
+        }
+
+        // Only a die's own face can be a critical hit or a fumble, not a compounded sum
+        let rule = match faces {
+            Faces::Numbered(sides) => self.options.crits.get(&sides),
+            _ => None,
+        };
+        if let Some(rule) = rule {
+            for die in rolled.iter_mut().skip(fixed).filter(|die| die.chain.is_empty()) {
+                die.critical = rule.critical.is_some_and(|on| on.matches(die.value));
+                die.fumble = rule.fumble.is_some_and(|on| on.matches(die.value));
+            }
+        }
+
+        Ok(DiceRoll {
+            // Doubled dice are shown as they were rolled, like `4d6` for `2d6`
+            notation: DiceExpr { count, ..dice.clone() }.to_string(),
+            sides: faces.sides(),
+            dice: rolled,
+            pool,
+            fudge: faces == Faces::Fudge,
+        })
+    }
+}
+
+/// A die that landed on the face at a position among its faces, counting from 0,
+/// shown by that face's label
+fn landed_on(faces: &Faces, index: usize) -> DieRoll {
+    let mut die = DieRoll::new(faces.value(index));
+    die.face = Some(index);
+    die.label = faces.label(index);
+    die
+}
+
+/// `count` dice showing set faces: their highest, for `Mode::Maximize`, or for
+/// `Mode::Average`, faces that add up to the group's average rounded down, each shown
+/// as the exact average of a die. Returns `None` if the group's total is too large.
+fn fixed_dice(faces: &Faces, count: u32, mode: Mode) -> Option<Vec<DieRoll>> {
+    if mode == Mode::Maximize {
+        // Of several highest faces, the first is shown
+        let die = match (faces, faces.position(faces.highest())) {
+            (&Faces::Numbered(_), _) | (_, None) => DieRoll::new(faces.highest()),
+            (_, Some(index)) => landed_on(faces, index),
+        };
+        return Some(vec![die; count as usize]);
+    }
+    let (sum, len) = match *faces {
+        Faces::Numbered(sides) => (i128::from(sides) * (i128::from(sides) + 1) / 2, i128::from(sides)),
+        Faces::Fudge => (0, 3),
+        Faces::Custom(ref faces) => (faces.iter().map(|face| i128::from(face.value)).sum(), faces.len() as i128),
+        Faces::Named(_) => unreachable!("named dice are looked up before rolling"),
+    };
+    if count == 0 {
+        return Some(Vec::new());
+    }
+    let total = i32::try_from((sum * i128::from(count)).div_euclid(len)).ok()?;
+    let count = count as i32;
+    let label = if sum % len == 0 { (sum / len).to_string() } else { format!("{:.1}", sum as f64 / len as f64) };
+    Some((0..count).map(|i| {
+        // Spread the total as evenly as possible
+        let mut die = DieRoll::new(total.div_euclid(count) + if i < total.rem_euclid(count) { 1 } else { 0 });
+        die.label = Some(label.clone());
+        die
+    }).collect())
+}
+
+/// Whether the rerolls that repeat would reroll every face of a die, so it could
+/// never stop rolling.
+fn rerolls_every_face(rerolls: &[(Reroll, Compare)], faces: &Faces) -> bool {
+    let always: Vec<Compare> = rerolls.iter()
+        .filter(|&&(reroll, _)| reroll == Reroll::Always)
+        .map(|&(_, on)| on)
+        .collect();
+    if always.is_empty() {
+        return false;
+    }
+    if let Faces::Custom(ref faces) = *faces {
+        return faces.iter().all(|face| always.iter().any(|on| on.matches(face.value)));
+    }
+    // The faces left alone form ranges that start at the lowest face or just beside a
+    // target, so if none of those faces are left alone, no face is.
+    let mut candidates = vec![faces.lowest()];
+    for on in &always {
+        candidates.extend_from_slice(&[on.target.saturating_sub(1), on.target, on.target.saturating_add(1)]);
+    }
+    !candidates.into_iter()
+        .filter(|&face| face >= faces.lowest() && face <= faces.highest())
+        .any(|face| !always.iter().any(|on| on.matches(face)))
+}
+
+/// Divide, rounding towards negative infinity as D&D does.
+pub(crate) fn floor_div(lhs: i32, rhs: i32) -> Option<i32> {
+    let quotient = lhs.checked_div(rhs)?;
+    if (lhs % rhs != 0) && ((lhs < 0) != (rhs < 0)) {
+        quotient.checked_sub(1)
+    } else {
+        Some(quotient)
+    }
+}
+
+/// Mark `count` of the highest or lowest dice that are still kept as dropped.
+/// Ties are broken in favour of dropping the die rolled first.
+fn drop_dice(rolled: &mut [DieRoll], selection: Selection, count: usize) {
+    let mut candidates: Vec<usize> = (0..rolled.len()).filter(|&i| rolled[i].kept).collect();
+    match selection {
+        Selection::Lowest => candidates.sort_by_key(|&i| rolled[i].value),
+        Selection::Highest => candidates.sort_by_key(|&i| -rolled[i].value),
+    }
+    for &i in candidates.iter().take(count) {
+        rolled[i].kept = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dice::parser::parse;
+    use rng;
+
+    /// How many seeds each property is checked with
+    const SEEDS: u64 = 200;
+
+    fn evaluate_seeded(input: &str, seed: u64, options: &EvalOptions) -> Evaluation {
+        evaluate(&parse(input).unwrap(), &mut rng::seeded(seed), options).unwrap()
+    }
+
+    /// The value and the groups of dice rolled by an input, for each seed in turn
+    fn rolls(input: &str) -> Vec<(i32, Vec<DiceRoll>)> {
+        (0..SEEDS).map(|seed| {
+            let evaluation = evaluate_seeded(input, seed, &EvalOptions::default());
+            let groups = evaluation.terms.into_iter().filter_map(|term| match term {
+                Term::Dice(dice) => Some(dice),
+                _ => None,
+            }).collect();
+            (evaluation.value, groups)
+        }).collect()
+    }
+
+    fn value(input: &str) -> i32 {
+        evaluate_seeded(input, 0, &EvalOptions::default()).value
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(value("1 + 2 * 3"), 7);
+        assert_eq!(value("(1 + 2) * 3"), 9);
+        assert_eq!(value("7 / 2"), 3);
+        assert_eq!(value("-7 / 2"), -4);
+        let e = evaluate(&parse("1 / (2 - 2)").unwrap(), &mut rng::seeded(0), &EvalOptions::default()).unwrap_err();
+        assert_eq!(e.kind, ErrorKind::DivisionByZero);
+    }
+
+    #[test]
+    fn dice_land_on_their_faces() {
+        for (value, groups) in rolls("3d6") {
+            assert_eq!(groups[0].dice.len(), 3);
+            assert!(groups[0].dice.iter().all(|die| (1..=6).contains(&die.value)));
+            assert_eq!(value, groups[0].total());
+        }
+    }
+
+    #[test]
+    fn keep_highest_keeps_the_highest() {
+        for (value, groups) in rolls("2d20kh") {
+            let dice = &groups[0].dice;
+            assert_eq!(dice.iter().filter(|die| die.kept).count(), 1);
+            assert_eq!(value, dice.iter().map(|die| die.value).max().unwrap());
+        }
+    }
+
+    #[test]
+    fn drop_lowest_drops_the_lowest() {
+        for (value, groups) in rolls("4d6dl1") {
+            let mut faces: Vec<i32> = groups[0].dice.iter().map(|die| die.value).collect();
+            faces.sort();
+            assert_eq!(value, faces[1..].iter().sum::<i32>());
+        }
+    }
+
+    #[test]
+    fn exploding_dice_roll_again_on_their_highest_face() {
+        let mut exploded = false;
+        for (value, groups) in rolls("1d6!") {
+            let dice = &groups[0].dice;
+            for (i, die) in dice.iter().enumerate() {
+                assert_eq!(die.exploded, die.value == 6);
+                assert_eq!(die.exploded, i + 1 < dice.len());
+            }
+            exploded |= dice.len() > 1;
+            assert_eq!(value, groups[0].total());
+        }
+        assert!(exploded);
+    }
+
+    #[test]
+    fn compounding_dice_add_up_their_chain() {
+        let mut compounded = false;
+        for (value, groups) in rolls("1d6!!") {
+            let die = &groups[0].dice[0];
+            assert_eq!(groups[0].dice.len(), 1);
+            if !die.chain.is_empty() {
+                compounded = true;
+                assert_eq!(die.value, die.chain.iter().sum::<i32>());
+                assert!(die.chain[..die.chain.len() - 1].iter().all(|&face| face == 6));
+            }
+            assert_eq!(value, die.value);
+        }
+        assert!(compounded);
+    }
+
+    #[test]
+    fn penetrating_dice_count_one_less() {
+        let mut penetrated = false;
+        for (value, groups) in rolls("1d6!p") {
+            let dice = &groups[0].dice;
+            assert_eq!(value, dice.iter().map(|die| die.value).sum::<i32>());
+            // Extra dice count one less, so a 6 on one shows 5
+            assert!(dice[1..].iter().all(|die| (0..=5).contains(&die.value)));
+            penetrated |= dice.len() > 1;
+        }
+        assert!(penetrated);
+    }
+
+    #[test]
+    fn explosions_stop_at_the_limit() {
+        let options = EvalOptions { explosion_limit: 5, ..EvalOptions::default() };
+        let evaluation = evaluate_seeded("1d6!>0", 0, &options);
+        match evaluation.terms[0] {
+            Term::Dice(ref dice) => assert_eq!(dice.dice.len(), 6),
+            ref term => panic!("rolled {:?}", term),
+        }
+    }
+
+    #[test]
+    fn rerolling_keeps_going_until_the_condition_fails() {
+        let mut rerolled = false;
+        for (_, groups) in rolls("1d4r<3") {
+            let die = &groups[0].dice[0];
+            assert!(die.value >= 3);
+            assert!(die.rerolled.iter().all(|&face| face < 3));
+            rerolled |= !die.rerolled.is_empty();
+        }
+        assert!(rerolled);
+    }
+
+    #[test]
+    fn rerolling_once_keeps_the_new_face() {
+        let mut kept_low = false;
+        for (_, groups) in rolls("1d4ro<3") {
+            let die = &groups[0].dice[0];
+            match die.rerolled[..] {
+                [] => assert!(die.value >= 3),
+                [replaced] => assert!(replaced < 3),
+                ref rerolled => panic!("rerolled {:?}", rerolled),
+            }
+            kept_low |= !die.rerolled.is_empty() && die.value < 3;
+        }
+        assert!(kept_low);
+    }
+
+    #[test]
+    fn rerolls_stop_at_the_limit() {
+        let options = EvalOptions { reroll_limit: 50, ..EvalOptions::default() };
+        let e = evaluate(&parse("1d6 + 10d2147483647r<2147483647").unwrap(), &mut rng::seeded(0), &options).unwrap_err();
+        assert_eq!((e.kind, e.span), (ErrorKind::TooManyRerolls(50), Span::new(6, 31)));
+        assert!(evaluate(&parse("20d6r<3").unwrap(), &mut rng::seeded(0), &options).is_ok());
+    }
+
+    #[test]
+    fn endless_rerolls_are_refused() {
+        let e = evaluate(&parse("1d6r<7").unwrap(), &mut rng::seeded(0), &EvalOptions::default()).unwrap_err();
+        assert_eq!(e.kind, ErrorKind::EndlessReroll);
+    }
+
+    #[test]
+    fn pools_count_successes_less_failures() {
+        for seed in 0..SEEDS {
+            let evaluation = evaluate_seeded("6d10>=8f1", seed, &EvalOptions::default());
+            let pool = evaluation.pool.unwrap();
+            assert_eq!(evaluation.value, pool.successes as i32 - pool.failures as i32);
+            assert_eq!(pool.botch, pool.successes == 0 && pool.failures > 0);
+            match evaluation.terms[0] {
+                Term::Dice(ref dice) => {
+                    assert!(dice.pool);
+                    assert_eq!(pool.successes as usize, dice.dice.iter().filter(|die| die.value >= 8).count());
+                    assert_eq!(pool.failures as usize, dice.dice.iter().filter(|die| die.value == 1).count());
+                },
+                ref term => panic!("rolled {:?}", term),
+            }
+        }
+    }
+
+    #[test]
+    fn checks_measure_the_margin() {
+        let evaluation = evaluate_seeded("10 + 5 >= 12", 0, &EvalOptions::default());
+        let check = evaluation.check.unwrap();
+        assert!(check.passed);
+        assert_eq!((check.target, check.margin), (12, 3));
+        let check = evaluate_seeded("10 <= 8", 0, &EvalOptions::default()).check.unwrap();
+        assert!(!check.passed);
+        assert_eq!(check.margin, -2);
+    }
+
+    #[test]
+    fn modes_fix_or_double_the_dice() {
+        let with_mode = |mode| EvalOptions { mode, ..EvalOptions::default() };
+        assert_eq!(evaluate_seeded("2d6 + 3", 0, &with_mode(Mode::Average)).value, 10);
+        assert_eq!(evaluate_seeded("2d6 + 3", 0, &with_mode(Mode::Maximize)).value, 15);
+        for seed in 0..SEEDS {
+            let evaluation = evaluate_seeded("2d6 + 3", seed, &with_mode(Mode::Critical));
+            match evaluation.terms[0] {
+                Term::Dice(ref dice) => assert_eq!(dice.dice.len(), 4),
+                ref term => panic!("rolled {:?}", term),
+            }
+            assert!((7..=27).contains(&evaluation.value));
+        }
+    }
+
+    #[test]
+    fn faces_are_shown_by_their_own_labels() {
+        let mut seen = Vec::new();
+        for (_, groups) in rolls("d{blank=0,blank=0,advantage=1,success=1}") {
+            let die = &groups[0].dice[0];
+            let label = die.label.clone().unwrap();
+            assert_eq!(label, ["blank", "blank", "advantage", "success"][die.face.unwrap()]);
+            seen.push(label);
+        }
+        assert!(seen.iter().any(|label| label == "advantage") && seen.iter().any(|label| label == "success"));
+    }
+
+    #[test]
+    fn natural_twenties_are_critical_hits() {
+        for seed in 0..SEEDS {
+            let evaluation = evaluate_seeded("1d20", seed, &EvalOptions::default());
+            assert_eq!(evaluation.critical, evaluation.value == 20);
+            assert_eq!(evaluation.fumble, evaluation.value == 1);
+        }
+    }
+
+    #[test]
+    fn subtotals_add_up_after_division() {
+        let subtotals = |input: &str| evaluate_seeded(input, 0, &EvalOptions::default()).subtotals.iter()
+            .map(|subtotal| (subtotal.tag.clone(), subtotal.total)).collect::<Vec<_>>();
+        assert_eq!(subtotals("(3[fire] + 3[cold]) / 2"), vec![("fire".to_string(), 2), ("cold".to_string(), 1)]);
+        assert_eq!(subtotals("(3[fire] + 3) / 2"), vec![("fire".to_string(), 2)]);
+        assert_eq!(subtotals("(5[fire] + 5[cold]) / -2"), vec![("fire".to_string(), -2), ("cold".to_string(), -3)]);
+        assert_eq!(subtotals("7[fire] / 2"), vec![("fire".to_string(), 3)]);
+    }
+
+    #[test]
+    fn floor_div_rounds_down() {
+        assert_eq!(floor_div(7, 2), Some(3));
+        assert_eq!(floor_div(-7, 2), Some(-4));
+        assert_eq!(floor_div(7, -2), Some(-4));
+        assert_eq!(floor_div(1, 0), None);
+        assert_eq!(floor_div(i32::MIN, -1), None);
+    }
+}
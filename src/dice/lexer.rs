@@ -0,0 +1,199 @@
+use std::fmt;
+
+use dice::error::{ErrorKind, RollError, Span};
+
+/// The different kinds of token that can appear in a dice expression
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    /// A non-negative integer literal
+    Number(i32),
+    /// A run of letters, such as the `d` in `1d20`
+    Word(String),
+    /// A name following an `@`, such as the `hit` in `3@hit`. Names may contain
+    /// letters, digits and underscores.
+    Name(String),
+    /// A tag in brackets, such as the `fire` in `2d6[fire]`. Tags may contain letters,
+    /// digits, spaces, underscores and hyphens.
+    Tag(String),
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    OpenParen,
+    CloseParen,
+    OpenBrace,
+    CloseBrace,
+    Comma,
+    Bang,
+    Equal,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+}
+
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TokenKind::Number(n) => write!(f, "{}", n),
+            TokenKind::Word(ref w) => write!(f, "{}", w),
+            TokenKind::Name(ref name) => write!(f, "@{}", name),
+            TokenKind::Tag(ref tag) => write!(f, "[{}]", tag),
+            TokenKind::Plus => write!(f, "+"),
+            TokenKind::Minus => write!(f, "-"),
+            TokenKind::Star => write!(f, "*"),
+            TokenKind::Slash => write!(f, "/"),
+            TokenKind::OpenParen => write!(f, "("),
+            TokenKind::CloseParen => write!(f, ")"),
+            TokenKind::OpenBrace => write!(f, "{{"),
+            TokenKind::CloseBrace => write!(f, "}}"),
+            TokenKind::Comma => write!(f, ","),
+            TokenKind::Bang => write!(f, "!"),
+            TokenKind::Equal => write!(f, "="),
+            TokenKind::Greater => write!(f, ">"),
+            TokenKind::GreaterEqual => write!(f, ">="),
+            TokenKind::Less => write!(f, "<"),
+            TokenKind::LessEqual => write!(f, "<="),
+        }
+    }
+}
+
+/// A token, along with the part of the input it was read from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+/// Split an expression into tokens, skipping whitespace.
+pub fn tokenize(input: &str) -> Result<Vec<Token>, RollError> {
+    let mut tokens = Vec::new();
+    let mut chars = input.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        let kind = match c {
+            c if c.is_whitespace() => continue,
+            '+' => TokenKind::Plus,
+            '-' => TokenKind::Minus,
+            '*' => TokenKind::Star,
+            '/' => TokenKind::Slash,
+            '(' => TokenKind::OpenParen,
+            ')' => TokenKind::CloseParen,
+            '{' => TokenKind::OpenBrace,
+            '}' => TokenKind::CloseBrace,
+            ',' => TokenKind::Comma,
+            '!' => TokenKind::Bang,
+            '=' => TokenKind::Equal,
+            '>' | '<' => {
+                let or_equal = match chars.peek() {
+                    Some(&(_, '=')) => {
+                        chars.next();
+                        true
+                    },
+                    _ => false,
+                };
+                let kind = match (c, or_equal) {
+                    ('>', false) => TokenKind::Greater,
+                    ('>', true) => TokenKind::GreaterEqual,
+                    ('<', false) => TokenKind::Less,
+                    _ => TokenKind::LessEqual,
+                };
+                let end = if or_equal { start + 2 } else { start + 1 };
+                tokens.push(Token { kind, span: Span::new(start, end) });
+                continue;
+            },
+            '0'..='9' => {
+                let mut value = c.to_digit(10).unwrap() as i32;
+                let mut end = start + 1;
+                let mut overflowed = false;
+                while let Some(&(i, d)) = chars.peek() {
+                    let digit = match d.to_digit(10) {
+                        Some(digit) => digit as i32,
+                        None => break,
+                    };
+                    match value.checked_mul(10).and_then(|v| v.checked_add(digit)) {
+                        Some(v) => value = v,
+                        None => overflowed = true,
+                    }
+                    end = i + 1;
+                    chars.next();
+                }
+                if overflowed {
+                    return Err(RollError::new(ErrorKind::Overflow, Span::new(start, end)));
+                }
+                tokens.push(Token { kind: TokenKind::Number(value), span: Span::new(start, end) });
+                continue;
+            },
+            '@' => {
+                let mut name = String::new();
+                let mut end = start + 1;
+                while let Some(&(i, l)) = chars.peek() {
+                    if !(l.is_alphanumeric() || l == '_') {
+                        break;
+                    }
+                    name.push(l);
+                    end = i + l.len_utf8();
+                    chars.next();
+                }
+                if name.is_empty() {
+                    return Err(RollError::new(
+                        ErrorKind::UnexpectedToken { found: Some("@".to_string()), expected: "a name after `@`" },
+                        Span::new(start, end),
+                    ));
+                }
+                tokens.push(Token { kind: TokenKind::Name(name), span: Span::new(start, end) });
+                continue;
+            },
+            '[' => {
+                let mut tag = String::new();
+                let mut end = start + 1;
+                let mut closed = false;
+                for (i, l) in chars.by_ref() {
+                    end = i + l.len_utf8();
+                    match l {
+                        ']' => {
+                            closed = true;
+                            break;
+                        },
+                        l if l.is_alphanumeric() || l == ' ' || l == '_' || l == '-' => tag.push(l),
+                        l => return Err(RollError::new(
+                            ErrorKind::UnexpectedToken { found: Some(l.to_string()), expected: "a tag like `[fire]`" },
+                            Span::new(i, end),
+                        )),
+                    }
+                }
+                let tag = tag.trim();
+                if !closed || tag.is_empty() {
+                    let found = if closed { Some("]".to_string()) } else { None };
+                    return Err(RollError::new(
+                        ErrorKind::UnexpectedToken { found, expected: "a tag like `[fire]`" },
+                        Span::new(start, end),
+                    ));
+                }
+                tokens.push(Token { kind: TokenKind::Tag(tag.to_string()), span: Span::new(start, end) });
+                continue;
+            },
+            c if c.is_alphabetic() => {
+                let mut word = c.to_string();
+                let mut end = start + c.len_utf8();
+                while let Some(&(i, l)) = chars.peek() {
+                    if !l.is_alphabetic() {
+                        break;
+                    }
+                    word.push(l);
+                    end = i + l.len_utf8();
+                    chars.next();
+                }
+                tokens.push(Token { kind: TokenKind::Word(word), span: Span::new(start, end) });
+                continue;
+            },
+            c => return Err(RollError::new(
+                ErrorKind::UnexpectedToken { found: Some(c.to_string()), expected: "a dice expression" },
+                Span::new(start, start + c.len_utf8()),
+            )),
+        };
+        tokens.push(Token { kind, span: Span::new(start, start + c.len_utf8()) });
+    }
+
+    Ok(tokens)
+}
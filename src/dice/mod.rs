@@ -0,0 +1,18 @@
+//! The dice expression language: tokenizing, parsing and evaluating
+//! expressions like `(2d20 + 4) / 2 - (1d4 * 2)`.
+
+mod ast;
+mod breakdown;
+mod error;
+mod eval;
+mod lexer;
+mod parser;
+
+pub use self::ast::{BinOp, Compare, CompareOp, DiceExpr, Explosion, Expr, Face, Faces, Modifier, Reroll, Selection};
+pub use self::breakdown::{CheckResult, DiceRoll, DieRoll, PoolResult, Subtotal, Term};
+pub(crate) use self::breakdown::is_false;
+pub use self::error::{ErrorKind, RollError, Span};
+pub use self::eval::{default_crits, evaluate, resolve, CritDamage, CritRule, EvalOptions, Evaluation, Mode, MAX_DICE,
+                     MAX_REPEAT};
+pub(crate) use self::eval::floor_div;
+pub use self::parser::{parse, parse_faces};
@@ -0,0 +1,650 @@
+use dice::ast::{BinOp, Compare, CompareOp, DiceExpr, Explosion, Expr, Face, Faces, Modifier, Reroll, Selection};
+use dice::error::{ErrorKind, RollError, Span};
+use dice::lexer::{tokenize, Token, TokenKind};
+
+/// Parse a dice expression such as `(2d20 + 4) / 2 - (1d4 * 2)`.
+///
+/// The grammar, from loosest to tightest binding, is:
+///
+/// ```text
+/// check  := expr (('=' | '>' | '>=' | '<' | '<=' | 'vs') expr)?
+/// expr   := term (('+' | '-') term)*
+/// term   := unary (('*' | '/') unary)*
+/// unary  := '-' unary | atom tag?
+/// atom   := NUMBER | dice | '@' NAME | '(' expr ')'
+/// tag    := '[' NAME ']'
+/// dice   := NUMBER? ('d' faces | '@' NAME) modifier*
+/// faces  := NUMBER | 'F' | '{' label (',' label)* '}'
+/// label  := (WORD '=')? '-'? NUMBER
+/// modifier := ('kh' | 'kl' | 'k' | 'dh' | 'dl') NUMBER?
+///           | ('!' | '!!' | '!p') compare?
+///           | ('r' | 'ro') face
+///           | compare
+///           | 'f' face
+/// face     := compare | '-'? NUMBER
+/// compare  := ('=' | '>' | '>=' | '<' | '<=') '-'? NUMBER
+/// ```
+///
+/// A condition written straight after several dice, like `8d10>=8`, makes them a pool.
+/// Written with a space, like `8d10 >= 8`, it could just as well check their total, so
+/// it's refused; the dice go in parentheses to check it, like `(2d6) >= 7`. After a
+/// single die, like `1d20>=15` or `2d20kh >= 15`, a condition always checks the whole
+/// roll, spaced or not. A pool has at most one condition for successes and one for
+/// failures.
+///
+/// Letters are read regardless of case, so `4D6KH3` is the same as `4d6kh3`.
+///
+/// A name without a count or modifiers, like `@str_mod`, could be either some dice or
+/// a variable, so it's left for evaluation to decide.
+///
+/// A tag marks what kind of value a term is, like the damage types in
+/// `1d8[slashing] + 2d6[fire]`.
+pub fn parse(input: &str) -> Result<Expr, RollError> {
+    let tokens = tokenize(input)?;
+    if tokens.is_empty() {
+        return Err(RollError::new(ErrorKind::EmptyInput, Span::new(0, input.len())));
+    }
+
+    let mut parser = Parser { tokens, pos: 0, end: input.len() };
+    let expr = parser.check()?;
+
+    // Anything left over means the expression didn't parse all the way through.
+    if parser.peek().is_some() {
+        return Err(parser.unexpected("an operator"));
+    }
+
+    Ok(expr)
+}
+
+/// Parse a condition on a die's face, like `>=19`, or a single face, like `20`.
+pub fn parse_condition(input: &str) -> Result<Compare, RollError> {
+    let tokens = tokenize(input)?;
+    let mut parser = Parser { tokens, pos: 0, end: input.len() };
+    let mut span = Span::new(0, 0);
+    let compare = parser.face(&mut span, "a face or a condition like `>=19`")?;
+    if parser.peek().is_some() {
+        return Err(parser.unexpected("the end of the condition"));
+    }
+    Ok(compare)
+}
+
+/// Parse the faces of a kind of die, written as they would be in an expression but
+/// without a count or modifiers, like `d6`, `dF` or `d{1,1,2,3,5,8}`.
+pub fn parse_faces(input: &str) -> Result<Faces, RollError> {
+    let tokens = tokenize(input)?;
+    let mut parser = Parser { tokens, pos: 0, end: input.len() };
+    if !parser.at_dice() {
+        return Err(parser.unexpected("dice like `d6` or `d{1,2,3}`"));
+    }
+    let start = parser.peek().unwrap().span;
+    let (faces, _) = parser.faces(start)?;
+    if parser.peek().is_some() {
+        return Err(parser.unexpected("the end of the dice"));
+    }
+    Ok(faces)
+}
+
+/// Whether a word starts a group of Fudge dice, like the `dF` in `4dF`
+fn is_fudge(word: &str) -> bool {
+    word.get(..2).is_some_and(|start| start.eq_ignore_ascii_case("df"))
+}
+
+/// Whether a token compares, like the `>=` in `1d20 >= 15`
+fn is_compare(kind: &TokenKind) -> bool {
+    matches!(*kind, TokenKind::Equal | TokenKind::Greater | TokenKind::GreaterEqual | TokenKind::Less | TokenKind::LessEqual)
+}
+
+/// A recursive descent parser over the tokens of a single expression
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// The length of the input, used to point at the end when it runs out early
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        if token.is_some() {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn peek_kind(&self) -> Option<&TokenKind> {
+        self.peek().map(|t| &t.kind)
+    }
+
+    /// An empty span pointing at the end of the input
+    fn eof_span(&self) -> Span {
+        Span::new(self.end, self.end)
+    }
+
+    /// Report the next token (or the end of the input) as unexpected.
+    fn unexpected(&self, expected: &'static str) -> RollError {
+        match self.peek() {
+            Some(token) => {
+                let kind = match token.kind {
+                    TokenKind::CloseParen => ErrorKind::UnbalancedParens,
+                    _ => ErrorKind::UnexpectedToken { found: Some(token.kind.to_string()), expected },
+                };
+                RollError::new(kind, token.span)
+            },
+            None => RollError::new(ErrorKind::UnexpectedToken { found: None, expected }, self.eof_span()),
+        }
+    }
+
+    fn check(&mut self) -> Result<Expr, RollError> {
+        let roll = self.expr()?;
+        let op = match self.peek_kind() {
+            Some(TokenKind::Equal) => CompareOp::Equal,
+            Some(TokenKind::Greater) => CompareOp::Greater,
+            Some(TokenKind::GreaterEqual) => CompareOp::GreaterEqual,
+            Some(TokenKind::Less) => CompareOp::Less,
+            Some(TokenKind::LessEqual) => CompareOp::LessEqual,
+            // `vs` means meeting or beating the target
+            Some(TokenKind::Word(w)) if w.eq_ignore_ascii_case("vs") => CompareOp::GreaterEqual,
+            _ => return Ok(roll),
+        };
+        self.next();
+        let target = self.expr()?;
+        let span = roll.span().to(target.span());
+        Ok(Expr::Check { roll: Box::new(roll), op, target: Box::new(target), span })
+    }
+
+    fn expr(&mut self) -> Result<Expr, RollError> {
+        let mut lhs = self.term()?;
+        loop {
+            let op = match self.peek_kind() {
+                Some(&TokenKind::Plus) => BinOp::Add,
+                Some(&TokenKind::Minus) => BinOp::Sub,
+                _ => return Ok(lhs),
+            };
+            self.next();
+            let rhs = self.term()?;
+            let span = lhs.span().to(rhs.span());
+            lhs = Expr::Binary { op, lhs: Box::new(lhs), rhs: Box::new(rhs), span };
+        }
+    }
+
+    fn term(&mut self) -> Result<Expr, RollError> {
+        let mut lhs = self.unary()?;
+        loop {
+            let op = match self.peek_kind() {
+                Some(&TokenKind::Star) => BinOp::Mul,
+                Some(&TokenKind::Slash) => BinOp::Div,
+                _ => return Ok(lhs),
+            };
+            self.next();
+            let rhs = self.unary()?;
+            let span = lhs.span().to(rhs.span());
+            lhs = Expr::Binary { op, lhs: Box::new(lhs), rhs: Box::new(rhs), span };
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, RollError> {
+        if let Some(&TokenKind::Minus) = self.peek_kind() {
+            let minus = self.next().unwrap();
+            let operand = self.unary()?;
+            let span = minus.span.to(operand.span());
+            return Ok(Expr::Negate { operand: Box::new(operand), span });
+        }
+        let atom = self.atom()?;
+        match self.peek().cloned() {
+            Some(Token { kind: TokenKind::Tag(tag), span }) => {
+                self.next();
+                let span = atom.span().to(span);
+                Ok(Expr::Tagged { inner: Box::new(atom), tag, span })
+            },
+            _ => Ok(atom),
+        }
+    }
+
+    fn atom(&mut self) -> Result<Expr, RollError> {
+        let token = match self.peek().cloned() {
+            Some(token) => token,
+            None => return Err(self.unexpected("a number, dice or `(`")),
+        };
+
+        match token.kind {
+            TokenKind::Number(value) => {
+                self.next();
+                if self.at_dice() {
+                    self.dice(value as u32, token.span)
+                } else if let Some(TokenKind::Name(_)) = self.peek_kind() {
+                    self.named_dice(value as u32, token.span)
+                } else {
+                    Ok(Expr::Number { value, span: token.span })
+                }
+            },
+            TokenKind::Word(_) if self.at_dice() => self.dice(1, token.span),
+            TokenKind::Name(_) => match self.named_dice(1, token.span)? {
+                Expr::Dice(DiceExpr { faces: Faces::Named(name), ref modifiers, span, .. }) if modifiers.is_empty() =>
+                    Ok(Expr::Variable { name, span }),
+                dice => Ok(dice),
+            },
+            TokenKind::OpenParen => {
+                self.next();
+                let inner = self.expr()?;
+                match self.next() {
+                    Some(Token { kind: TokenKind::CloseParen, span }) => Ok(Expr::Group {
+                        inner: Box::new(inner),
+                        span: token.span.to(span),
+                    }),
+                    // Either the input ran out or the inner expression stopped early;
+                    // in both cases this parenthesis is the one left open.
+                    Some(_) => {
+                        self.pos -= 1;
+                        Err(self.unexpected("an operator or `)`"))
+                    },
+                    None => Err(RollError::new(ErrorKind::UnbalancedParens, token.span)),
+                }
+            },
+            _ => Err(self.unexpected("a number, dice or `(`")),
+        }
+    }
+
+    /// Whether the next token is the `d` of a dice group, or the `dF` of a group of
+    /// Fudge dice
+    fn at_dice(&self) -> bool {
+        match self.peek_kind() {
+            Some(TokenKind::Word(w)) => w == "d" || w == "D" || is_fudge(w),
+            _ => false,
+        }
+    }
+
+    /// Parse the `dM` part of a dice group, given the already-parsed count.
+    fn dice(&mut self, count: u32, start: Span) -> Result<Expr, RollError> {
+        let (faces, span) = self.faces(start)?;
+        self.modifiers(count, faces, span)
+    }
+
+    /// Parse the `@name` part of a group of named dice, given the already-parsed count.
+    fn named_dice(&mut self, count: u32, start: Span) -> Result<Expr, RollError> {
+        let (name, span) = match self.peek().cloned() {
+            Some(Token { kind: TokenKind::Name(name), span }) => (name, span),
+            _ => return Err(self.unexpected("the name of some dice")),
+        };
+        self.next();
+        self.modifiers(count, Faces::Named(name), start.to(span))
+    }
+
+    /// Parse a `d` and the faces after it, returning them with the span of the
+    /// dice so far.
+    fn faces(&mut self, start: Span) -> Result<(Faces, Span), RollError> {
+        let fudge = match self.peek_kind() {
+            Some(TokenKind::Word(w)) => is_fudge(w),
+            _ => false,
+        };
+        if fudge {
+            // Letters run together into one word, so any modifier written straight
+            // after `dF`, like the `dl` in `4dFdl1`, needs splitting off
+            self.split_word(2);
+            let span = start.to(self.next().unwrap().span);
+            return Ok((Faces::Fudge, span));
+        }
+
+        // Skip the `d`
+        self.next();
+        match self.peek().cloned() {
+            Some(Token { kind: TokenKind::Number(sides), span }) if sides > 0 => {
+                self.next();
+                Ok((Faces::Numbered(sides as u32), start.to(span)))
+            },
+            Some(Token { kind: TokenKind::Number(_), .. }) =>
+                Err(self.unexpected("a number of sides greater than zero")),
+            Some(Token { kind: TokenKind::OpenBrace, .. }) => self.face_list(start),
+            _ => Err(self.unexpected("a number of sides")),
+        }
+    }
+
+    /// Parse a list of faces in braces, like `{1,1,2,3,5,8}` or `{miss=0,hit=1}`.
+    fn face_list(&mut self, start: Span) -> Result<(Faces, Span), RollError> {
+        let mut span = start.to(self.next().unwrap().span);
+        let mut faces = Vec::new();
+        loop {
+            let label = match self.peek().cloned() {
+                Some(Token { kind: TokenKind::Word(label), .. }) => {
+                    self.next();
+                    match self.peek_kind() {
+                        Some(TokenKind::Equal) => self.next(),
+                        _ => return Err(self.unexpected("`=` and the value of the face")),
+                    };
+                    Some(label)
+                },
+                _ => None,
+            };
+            match self.face_number(&mut span)? {
+                Some(value) => faces.push(Face { value, label }),
+                None => return Err(self.unexpected("a face")),
+            }
+            match self.peek_kind() {
+                Some(TokenKind::Comma) => {
+                    self.next();
+                },
+                Some(TokenKind::CloseBrace) => {
+                    let end = self.next().unwrap().span;
+                    return Ok((Faces::Custom(faces), span.to(end)));
+                },
+                _ => return Err(self.unexpected("`,` or `}`")),
+            }
+        }
+    }
+
+    /// Parse the modifiers following a group of dice, completing the group.
+    fn modifiers(&mut self, count: u32, faces: Faces, mut span: Span) -> Result<Expr, RollError> {
+        let mut modifiers: Vec<Modifier> = Vec::new();
+        while let Some(start) = self.peek().map(|token| token.span.start) {
+            // How many dice are counted, after any kept or dropped so far
+            let counted = modifiers.iter().fold(count, |counted, modifier| match *modifier {
+                Modifier::Keep(_, keep) => counted.min(keep),
+                Modifier::Drop(_, drop) => counted.saturating_sub(drop),
+                _ => counted,
+            });
+            let successes = modifiers.iter().any(|m| matches!(*m, Modifier::Success(_)));
+            let modifier = match self.modifier(&mut span, counted > 1, successes)? {
+                Some(modifier) => modifier,
+                None => break,
+            };
+            let repeated = match modifier {
+                Modifier::Success(_) => modifiers.iter().any(|m| matches!(*m, Modifier::Success(_))),
+                Modifier::Failure(_) => modifiers.iter().any(|m| matches!(*m, Modifier::Failure(_))),
+                _ => false,
+            };
+            if repeated {
+                return Err(RollError::new(ErrorKind::RepeatedCondition, Span::new(start, span.end)));
+            }
+            modifiers.push(modifier);
+        }
+
+        Ok(Expr::Dice(DiceExpr { count, faces, modifiers, span }))
+    }
+
+    /// Split the word at the current position in two, after its first `len` bytes.
+    fn split_word(&mut self, len: usize) {
+        let (head, rest, span) = match self.peek() {
+            Some(&Token { kind: TokenKind::Word(ref word), span }) if word.len() > len =>
+                (word[..len].to_string(), word[len..].to_string(), span),
+            _ => return,
+        };
+        let split = span.start + len;
+        self.tokens[self.pos] = Token { kind: TokenKind::Word(head), span: Span::new(span.start, split) };
+        self.tokens.insert(self.pos + 1, Token { kind: TokenKind::Word(rest), span: Span::new(split, span.end) });
+    }
+
+    /// Parse a modifier following a group of dice, if there is one, extending the
+    /// group's span to cover it. Only a group of several dice can have a bare condition,
+    /// counting the dice that meet it as successes.
+    fn modifier(&mut self, span: &mut Span, several: bool, successes: bool) -> Result<Option<Modifier>, RollError> {
+        if let Some(TokenKind::Bang) = self.peek_kind() {
+            return self.explosion(span).map(Some);
+        }
+        // Written straight after the dice, a condition makes them a pool. After a space it
+        // could just as well check their total, unless they're a pool already.
+        match self.peek() {
+            Some(token) if several && token.span.start == span.end => {
+                if let Some(compare) = self.compare(span)? {
+                    return Ok(Some(Modifier::Success(compare)));
+                }
+            },
+            Some(token) if several && !successes && is_compare(&token.kind) =>
+                return Err(RollError::new(ErrorKind::AmbiguousCondition, token.span)),
+            _ => {},
+        }
+
+        let (make, selection): (fn(Selection, u32) -> Modifier, Selection) = match self.peek_kind() {
+            Some(TokenKind::Word(w)) => match w.to_ascii_lowercase().as_str() {
+                "kh" | "k" => (Modifier::Keep, Selection::Highest),
+                "kl" => (Modifier::Keep, Selection::Lowest),
+                "dh" => (Modifier::Drop, Selection::Highest),
+                "dl" => (Modifier::Drop, Selection::Lowest),
+                "r" => return self.reroll(Reroll::Always, span).map(Some),
+                "ro" => return self.reroll(Reroll::Once, span).map(Some),
+                "f" => {
+                    *span = span.to(self.next().unwrap().span);
+                    return self.face(span, "a face to count as a failure").map(|on| Some(Modifier::Failure(on)));
+                },
+                _ => return Ok(None),
+            },
+            _ => return Ok(None),
+        };
+        *span = span.to(self.next().unwrap().span);
+
+        // The number of dice defaults to one, as in `2d20kh`
+        let count = match self.peek().cloned() {
+            Some(Token { kind: TokenKind::Number(count), span: number }) => {
+                self.next();
+                *span = span.to(number);
+                count as u32
+            },
+            _ => 1,
+        };
+        Ok(Some(make(selection, count)))
+    }
+
+    /// Parse an explosion modifier: `!`, `!!` or `!p`, optionally followed by the
+    /// condition for exploding.
+    fn explosion(&mut self, span: &mut Span) -> Result<Modifier, RollError> {
+        *span = span.to(self.next().unwrap().span);
+        let explosion = match self.peek_kind() {
+            Some(TokenKind::Bang) => Explosion::Compounding,
+            Some(TokenKind::Word(w)) if w.eq_ignore_ascii_case("p") => Explosion::Penetrating,
+            _ => Explosion::Standard,
+        };
+        if explosion != Explosion::Standard {
+            *span = span.to(self.next().unwrap().span);
+        }
+        let compare = self.compare(span)?;
+        Ok(Modifier::Explode(explosion, compare))
+    }
+
+    /// Parse a reroll modifier: `r` or `ro`, followed by either the condition for
+    /// rerolling or the single face to reroll.
+    fn reroll(&mut self, reroll: Reroll, span: &mut Span) -> Result<Modifier, RollError> {
+        *span = span.to(self.next().unwrap().span);
+        self.face(span, "a face to reroll").map(|on| Modifier::Reroll(reroll, on))
+    }
+
+    /// Parse either a condition on a die's face or a single face, which must match
+    /// exactly.
+    fn face(&mut self, span: &mut Span, expected: &'static str) -> Result<Compare, RollError> {
+        if let Some(compare) = self.compare(span)? {
+            return Ok(compare);
+        }
+        match self.face_number(span)? {
+            Some(target) => Ok(Compare { op: CompareOp::Equal, target }),
+            None => Err(self.unexpected(expected)),
+        }
+    }
+
+    /// Parse a condition on a die's face, like `>=8`, if there is one.
+    fn compare(&mut self, span: &mut Span) -> Result<Option<Compare>, RollError> {
+        let op = match self.peek_kind() {
+            Some(TokenKind::Equal) => CompareOp::Equal,
+            Some(TokenKind::Greater) => CompareOp::Greater,
+            Some(TokenKind::GreaterEqual) => CompareOp::GreaterEqual,
+            Some(TokenKind::Less) => CompareOp::Less,
+            Some(TokenKind::LessEqual) => CompareOp::LessEqual,
+            _ => return Ok(None),
+        };
+        self.next();
+        match self.face_number(span)? {
+            Some(target) => Ok(Some(Compare { op, target })),
+            None => Err(self.unexpected("a number to compare against")),
+        }
+    }
+
+    /// Parse a face number, which may be negative for dice like Fudge dice, if there
+    /// is one.
+    fn face_number(&mut self, span: &mut Span) -> Result<Option<i32>, RollError> {
+        let negative = match self.peek_kind() {
+            Some(TokenKind::Minus) => {
+                self.next();
+                true
+            },
+            _ => false,
+        };
+        match self.peek().cloned() {
+            Some(Token { kind: TokenKind::Number(value), span: number }) => {
+                self.next();
+                *span = span.to(number);
+                Ok(Some(if negative { -value } else { value }))
+            },
+            _ if negative => Err(self.unexpected("a number")),
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The operator at the top of an expression, and its two sides as written
+    fn split(input: &str) -> (BinOp, String, String) {
+        match parse(input).unwrap() {
+            Expr::Binary { op, lhs, rhs, .. } => (op, lhs.to_string(), rhs.to_string()),
+            expr => panic!("`{}` parsed to {:?}", input, expr),
+        }
+    }
+
+    /// The modifiers of a single group of dice
+    fn modifiers(input: &str) -> Vec<Modifier> {
+        match parse(input).unwrap() {
+            Expr::Dice(dice) => dice.modifiers,
+            expr => panic!("`{}` parsed to {:?}", input, expr),
+        }
+    }
+
+    fn compare(op: CompareOp, target: i32) -> Compare {
+        Compare { op, target }
+    }
+
+    #[test]
+    fn multiplication_binds_tighter_than_addition() {
+        assert_eq!(split("1 + 2 * 3"), (BinOp::Add, "1".to_string(), "2 * 3".to_string()));
+        assert_eq!(split("1 * 2 - 3"), (BinOp::Sub, "1 * 2".to_string(), "3".to_string()));
+        assert_eq!(split("(1 + 2) * 3"), (BinOp::Mul, "(1 + 2)".to_string(), "3".to_string()));
+    }
+
+    #[test]
+    fn operators_associate_to_the_left() {
+        assert_eq!(split("1 - 2 - 3"), (BinOp::Sub, "1 - 2".to_string(), "3".to_string()));
+        assert_eq!(split("8 / 4 / 2"), (BinOp::Div, "8 / 4".to_string(), "2".to_string()));
+    }
+
+    #[test]
+    fn a_check_binds_loosest() {
+        match parse("1d20 + 5 vs 10 + 5").unwrap() {
+            Expr::Check { roll, op, target, .. } => {
+                assert_eq!(roll.to_string(), "1d20 + 5");
+                assert_eq!(op, CompareOp::GreaterEqual);
+                assert_eq!(target.to_string(), "10 + 5");
+            },
+            expr => panic!("parsed to {:?}", expr),
+        }
+    }
+
+    #[test]
+    fn spans_cover_what_was_parsed() {
+        let expr = parse("(2d20 + 4) / 2").unwrap();
+        assert_eq!(expr.span(), Span::new(0, 14));
+        match expr {
+            Expr::Binary { lhs, rhs, .. } => {
+                assert_eq!(lhs.span(), Span::new(0, 10));
+                assert_eq!(rhs.span(), Span::new(13, 14));
+            },
+            expr => panic!("parsed to {:?}", expr),
+        }
+        assert_eq!(parse("  4d6dl1").unwrap().span(), Span::new(2, 8));
+    }
+
+    #[test]
+    fn errors_point_at_the_problem() {
+        assert_eq!(parse("1d6 +").unwrap_err().span, Span::new(5, 5));
+        assert_eq!(parse("1d6 + * 2").unwrap_err().span, Span::new(6, 7));
+        assert_eq!(parse("").unwrap_err().kind, ErrorKind::EmptyInput);
+    }
+
+    #[test]
+    fn keep_and_drop() {
+        assert_eq!(modifiers("2d20kh"), vec![Modifier::Keep(Selection::Highest, 1)]);
+        assert_eq!(modifiers("2d20kl1"), vec![Modifier::Keep(Selection::Lowest, 1)]);
+        assert_eq!(modifiers("4d6k3"), vec![Modifier::Keep(Selection::Highest, 3)]);
+        assert_eq!(modifiers("4d6dl1"), vec![Modifier::Drop(Selection::Lowest, 1)]);
+        assert_eq!(modifiers("4d6dh2"), vec![Modifier::Drop(Selection::Highest, 2)]);
+    }
+
+    #[test]
+    fn explosions() {
+        assert_eq!(modifiers("1d6!"), vec![Modifier::Explode(Explosion::Standard, None)]);
+        assert_eq!(modifiers("1d6!!"), vec![Modifier::Explode(Explosion::Compounding, None)]);
+        assert_eq!(modifiers("1d6!p"), vec![Modifier::Explode(Explosion::Penetrating, None)]);
+        assert_eq!(modifiers("1d10!>8"),
+                   vec![Modifier::Explode(Explosion::Standard, Some(compare(CompareOp::Greater, 8)))]);
+    }
+
+    #[test]
+    fn rerolls() {
+        assert_eq!(modifiers("1d10r1"), vec![Modifier::Reroll(Reroll::Always, compare(CompareOp::Equal, 1))]);
+        assert_eq!(modifiers("2d6ro<2"), vec![Modifier::Reroll(Reroll::Once, compare(CompareOp::Less, 2))]);
+    }
+
+    #[test]
+    fn pools() {
+        assert_eq!(modifiers("8d10>=8"), vec![Modifier::Success(compare(CompareOp::GreaterEqual, 8))]);
+        assert_eq!(modifiers("6d10>=8f1"), vec![
+            Modifier::Success(compare(CompareOp::GreaterEqual, 8)),
+            Modifier::Failure(compare(CompareOp::Equal, 1)),
+        ]);
+        assert_eq!(modifiers("10d10>=8!"), vec![
+            Modifier::Success(compare(CompareOp::GreaterEqual, 8)),
+            Modifier::Explode(Explosion::Standard, None),
+        ]);
+    }
+
+    #[test]
+    fn a_pool_has_one_condition_of_each_kind() {
+        let e = parse("2d6>=3>=3").unwrap_err();
+        assert_eq!(e.kind, ErrorKind::RepeatedCondition);
+        assert_eq!(e.span.start, 6);
+        assert_eq!(parse("6d10>=8f1f2").unwrap_err().kind, ErrorKind::RepeatedCondition);
+    }
+
+    #[test]
+    fn spaces_never_change_what_a_condition_means() {
+        // After a single die, a condition checks the roll either way
+        assert_eq!(parse("1d20>=15").unwrap().to_string(), "1d20 >= 15");
+        assert_eq!(parse("2d20kh>15").unwrap().to_string(), "2d20kh1 > 15");
+        // After several, it's only a pool written straight after them
+        assert!(matches!(parse("(2d6) >= 7").unwrap(), Expr::Check { .. }));
+        assert!(matches!(parse("2d6 vs 7").unwrap(), Expr::Check { .. }));
+        let e = parse("1d4 + 2d6 >= 7").unwrap_err();
+        assert_eq!((e.kind, e.span), (ErrorKind::AmbiguousCondition, Span::new(10, 12)));
+        // A pool's successes can still be checked
+        assert!(matches!(parse("8d10>=8 >= 3").unwrap(), Expr::Check { .. }));
+    }
+
+    #[test]
+    fn keywords_ignore_case() {
+        assert_eq!(parse("4D6KH3").unwrap(), parse("4d6kh3").unwrap());
+        assert_eq!(parse("1D6!P").unwrap(), parse("1d6!p").unwrap());
+        assert_eq!(parse("2d6RO1").unwrap(), parse("2d6ro1").unwrap());
+        assert_eq!(parse("4DF").unwrap(), parse("4dF").unwrap());
+        assert_eq!(parse("1d20 VS 15").unwrap(), parse("1d20 vs 15").unwrap());
+    }
+
+    #[test]
+    fn custom_faces() {
+        let faces = parse_faces("d{miss=0,hit=1,-1}").unwrap();
+        assert_eq!(faces, Faces::Custom(vec![
+            Face { value: 0, label: Some("miss".to_string()) },
+            Face { value: 1, label: Some("hit".to_string()) },
+            Face { value: -1, label: None },
+        ]));
+    }
+}
@@ -0,0 +1,214 @@
+//! Writing the roll history out for use elsewhere, like a campaign wiki or a spreadsheet.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use csv;
+use serde_json;
+
+use format;
+use roll::RollOutcome;
+
+/// The formats the history can be exported to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// Comma separated values, with a header row
+    Csv,
+    /// One JSON object per line
+    JsonLines,
+    /// A Markdown table
+    Markdown,
+}
+
+impl ExportFormat {
+    /// Every export format, in the order they should be offered
+    pub fn all() -> &'static [ExportFormat] {
+        &[ExportFormat::Csv, ExportFormat::JsonLines, ExportFormat::Markdown]
+    }
+
+    /// A human readable name for the format
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ExportFormat::Csv => "CSV",
+            ExportFormat::JsonLines => "JSON Lines",
+            ExportFormat::Markdown => "Markdown",
+        }
+    }
+
+    /// The file extension conventionally used for the format
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::JsonLines => "jsonl",
+            ExportFormat::Markdown => "md",
+        }
+    }
+
+    /// Guess the format from a file's extension.
+    pub fn from_path(path: &Path) -> Option<ExportFormat> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "csv" => Some(ExportFormat::Csv),
+            "jsonl" | "json" => Some(ExportFormat::JsonLines),
+            "md" | "markdown" => Some(ExportFormat::Markdown),
+            _ => None,
+        }
+    }
+}
+
+/// The columns written for each roll
+#[derive(Serialize)]
+struct Record<'a> {
+    timestamp: String,
+    descriptor: &'a str,
+    breakdown: String,
+    outcome: i32,
+    /// The result as shown in the history, like `17 PASS (+2)` or `3 successes`
+    result: String,
+    critical: bool,
+    fumble: bool,
+    /// The entry rolled, for rolls on tables
+    text: Option<&'a str>,
+    seed: Option<u64>,
+}
+
+impl<'a> Record<'a> {
+    fn new(roll: &'a RollOutcome) -> Record<'a> {
+        Record {
+            timestamp: roll.timestamp.format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+            descriptor: &roll.descriptor,
+            breakdown: roll.breakdown(),
+            outcome: roll.outcome,
+            result: format::result(roll),
+            critical: roll.critical,
+            fumble: roll.fumble,
+            text: roll.text.as_deref(),
+            seed: roll.seed,
+        }
+    }
+}
+
+/// Write rolls, oldest first, in the given format.
+pub fn export<'a, I, W>(rolls: I, format: ExportFormat, out: W) -> io::Result<()>
+    where I: IntoIterator<Item = &'a RollOutcome>, W: Write
+{
+    match format {
+        ExportFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            for roll in rolls {
+                writer.serialize(Record::new(roll))?;
+            }
+            writer.flush()
+        },
+        ExportFormat::JsonLines => {
+            let mut out = out;
+            for roll in rolls {
+                serde_json::to_writer(&mut out, &Record::new(roll))?;
+                out.write_all(b"\n")?;
+            }
+            out.flush()
+        },
+        ExportFormat::Markdown => {
+            let mut out = out;
+            writeln!(out, "| Time | Specification | Breakdown | Result |")?;
+            writeln!(out, "|------|---------------|-----------|-------:|")?;
+            for roll in rolls {
+                let record = Record::new(roll);
+                writeln!(out, "| {} | {} | {} | {} |",
+                    record.timestamp,
+                    escape_markdown(record.descriptor),
+                    escape_markdown(&record.breakdown),
+                    escape_markdown(&markdown_result(&record)))?;
+            }
+            out.flush()
+        },
+    }
+}
+
+/// Write rolls to a file, overwriting it if it exists.
+pub fn export_to_file<'a, I>(rolls: I, format: ExportFormat, path: &Path) -> io::Result<()>
+    where I: IntoIterator<Item = &'a RollOutcome>
+{
+    export(rolls, format, BufWriter::new(File::create(path)?))
+}
+
+/// The Result cell of a roll's row in a Markdown table, noting any critical hit or
+/// fumble, like `20 PASS (+5), critical hit`.
+fn markdown_result(record: &Record) -> String {
+    match (record.critical, record.fumble) {
+        (true, false) => format!("{}, critical hit", record.result),
+        (false, true) => format!("{}, fumble", record.result),
+        (true, true) => format!("{}, critical hit and fumble", record.result),
+        (false, false) => record.result.clone(),
+    }
+}
+
+/// Escape the characters that would break out of a Markdown table cell or be
+/// mistaken for formatting. Tildes are left alone so dropped dice are struck out.
+fn escape_markdown(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '|' | '*' | '_' | '`' | '[' | ']' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            },
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use roll::{roll_seeded, RollOptions};
+
+    fn exported(rolls: &[RollOutcome], format: ExportFormat) -> String {
+        let mut out = Vec::new();
+        export(rolls, format, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn outcome(s: &str) -> RollOutcome {
+        roll_seeded(s, 0, &RollOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn csv_has_a_column_for_each_field() {
+        let csv = exported(&[outcome("12 + 5 >= 15")], ExportFormat::Csv);
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some("timestamp,descriptor,breakdown,outcome,result,critical,fumble,text,seed"));
+        assert!(lines.next().unwrap().ends_with(",12 + 5 >= 15,12 + 5 >= 15,17,17 PASS (+2),false,false,,0"));
+    }
+
+    #[test]
+    fn json_lines_hold_one_roll_each() {
+        let jsonl = exported(&[outcome("1d20"), outcome("2d6 + 3")], ExportFormat::JsonLines);
+        assert_eq!(jsonl.lines().count(), 2);
+        let first: serde_json::Value = serde_json::from_str(jsonl.lines().next().unwrap()).unwrap();
+        assert_eq!(first["descriptor"], "1d20");
+        assert_eq!(first["result"], first["outcome"].to_string());
+    }
+
+    #[test]
+    fn markdown_results_note_critical_hits() {
+        let critical = RollOutcome { critical: true, ..outcome("20 vs 15") };
+        let markdown = exported(&[critical], ExportFormat::Markdown);
+        assert!(markdown.lines().nth(2).unwrap().ends_with("| 20 PASS (+5), critical hit |"));
+    }
+
+    #[test]
+    fn markdown_cells_are_escaped() {
+        assert_eq!(escape_markdown("1d8[fire] | *x*"), "1d8\\[fire\\] \\| \\*x\\*");
+        assert_eq!(escape_markdown("~1~"), "~1~");
+    }
+
+    #[test]
+    fn formats_are_chosen_by_extension() {
+        assert_eq!(ExportFormat::from_path(Path::new("rolls.CSV")), Some(ExportFormat::Csv));
+        assert_eq!(ExportFormat::from_path(Path::new("rolls.md")), Some(ExportFormat::Markdown));
+        assert_eq!(ExportFormat::from_path(Path::new("rolls.txt")), None);
+    }
+}
@@ -0,0 +1,222 @@
+//! Turning roll outcomes into text for display.
+
+use chrono::Local;
+
+use dice::Term;
+use roll::RollOutcome;
+
+/// Render a list of terms the way the expression was written, with each dice group's
+/// faces shown after it: `(2d20 [3, 17] + 4) / 2`.
+pub fn breakdown(terms: &[Term]) -> String {
+    let mut out = String::new();
+    // Whether the next term should be written directly after the previous one
+    let mut glued = true;
+    for term in terms {
+        match *term {
+            Term::CloseParen => {},
+            _ if !glued => out.push(' '),
+            _ => {},
+        }
+        out.push_str(&term.to_string());
+        glued = matches!(*term, Term::OpenParen | Term::Negate);
+    }
+    out
+}
+
+/// The text shown in the Result column for an outcome. Pools show their successes,
+/// like `3 successes`, `Botch` or `6 successes (exceptional)`, and checks add whether
+/// they passed and by how much, like `17 PASS (+2)`.
+pub fn result(outcome: &RollOutcome) -> String {
+    with_check(outcome, value(outcome))
+}
+
+/// The value of an outcome, without the result of any check. Rolls on tables show the
+/// number rolled and the entry it gave, like `7: 3 goblins`.
+fn value(outcome: &RollOutcome) -> String {
+    if let Some(ref text) = outcome.text {
+        return format!("{}: {}", outcome.outcome, text);
+    }
+    match outcome.pool {
+        Some(ref pool) if pool.botch => "Botch".to_string(),
+        Some(ref pool) => {
+            let noun = if outcome.outcome == 1 { "success" } else { "successes" };
+            if pool.exceptional {
+                format!("{} {} (exceptional)", outcome.outcome, noun)
+            } else {
+                format!("{} {}", outcome.outcome, noun)
+            }
+        },
+        None => outcome.outcome.to_string(),
+    }
+}
+
+/// Add the result of an outcome's check, if it has one, to the text of its value.
+pub fn with_check(outcome: &RollOutcome, value: String) -> String {
+    match outcome.check {
+        Some(ref check) => {
+            let verdict = if check.passed { "PASS" } else { "FAIL" };
+            format!("{} {} ({:+})", value, verdict, check.margin)
+        },
+        None => value,
+    }
+}
+
+/// How much of an outcome each tag accounts for, like `5 slashing, 7 fire`, if it had
+/// any tags. Any part that no tag accounts for comes last, like `3 untyped`.
+pub fn subtotals(outcome: &RollOutcome) -> Option<String> {
+    if outcome.subtotals.is_empty() {
+        return None;
+    }
+    let mut parts: Vec<String> = outcome.subtotals.iter().map(|subtotal| subtotal.to_string()).collect();
+    let tagged = outcome.subtotals.iter().fold(0i64, |sum, subtotal| sum + i64::from(subtotal.total));
+    let rest = i64::from(outcome.outcome) - tagged;
+    if rest != 0 {
+        parts.push(format!("{} untyped", rest));
+    }
+    Some(parts.join(", "))
+}
+
+/// The result of a batch of rolls as a whole, if there's a meaningful one. Only a
+/// batch that repeats one roll, like `6x 4d6dl1`, has one: how many passed, like
+/// `4 of 6 pass`, for a check; the total successes for a pool; and otherwise the
+/// total. Batches of different rolls, like an attack and its damage, or of rolls on
+/// tables, have no result as a whole.
+pub fn group_result(outcomes: &[&RollOutcome]) -> Option<String> {
+    let first = outcomes.first()?;
+    let repeated = outcomes.iter().all(|outcome| {
+        outcome.descriptor == first.descriptor && outcome.macro_name == first.macro_name && outcome.mode == first.mode
+    });
+    if !repeated || first.text.is_some() {
+        return None;
+    }
+    let total: i64 = outcomes.iter().map(|outcome| i64::from(outcome.outcome)).sum();
+    if first.check.is_some() {
+        let passed = outcomes.iter().filter(|outcome| outcome.check.is_some_and(|check| check.passed)).count();
+        Some(format!("{} of {} pass", passed, outcomes.len()))
+    } else if first.pool.is_some() {
+        Some(format!("{} {}", total, if total == 1 { "success" } else { "successes" }))
+    } else {
+        Some(total.to_string())
+    }
+}
+
+/// The results of each roll of a batch, like `[15, 12, 9]`.
+pub fn group_breakdown(outcomes: &[&RollOutcome]) -> String {
+    let results: Vec<String> = outcomes.iter().map(|outcome| result(outcome)).collect();
+    format!("[{}]", results.join(", "))
+}
+
+/// Whether an outcome was rolled only with Fudge dice, so it can be read on the FATE
+/// ladder.
+pub fn is_fate(outcome: &RollOutcome) -> bool {
+    let mut dice = outcome.terms.iter().filter_map(|term| match *term {
+        Term::Dice(ref dice) => Some(dice),
+        _ => None,
+    }).peekable();
+    outcome.pool.is_none() && dice.peek().is_some() && dice.all(|dice| dice.fudge)
+}
+
+/// A result described on the FATE ladder, like `Great (+4)`. Results off either end
+/// of the ladder take the name of that end.
+pub fn fate_ladder(value: i32) -> String {
+    let adjective = match value {
+        v if v >= 8 => "Legendary",
+        7 => "Epic",
+        6 => "Fantastic",
+        5 => "Superb",
+        4 => "Great",
+        3 => "Good",
+        2 => "Fair",
+        1 => "Average",
+        0 => "Mediocre",
+        -1 => "Poor",
+        _ => "Terrible",
+    };
+    format!("{} ({:+})", adjective, value)
+}
+
+/// When and from which seed an outcome was rolled, like
+/// `Rolled at 2018-07-21 14:03:12 from seed 1234`.
+pub fn details(outcome: &RollOutcome) -> String {
+    let time = outcome.timestamp.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S");
+    match outcome.seed {
+        Some(seed) => format!("Rolled at {} from seed {}", time, seed),
+        None => format!("Rolled at {}", time),
+    }
+}
+
+/// What was rolled: the descriptor, after the macro's name if it was a macro and
+/// followed by its resolved form if it had variables, like
+/// `#longsword: 1d20 + @str_mod → 1d20 + 3`. Rolls that weren't made normally end with
+/// their mode's suffix, like `2d6 + 3 crit`.
+pub fn specification(outcome: &RollOutcome) -> String {
+    let mut specification = match outcome.macro_name {
+        Some(ref name) => format!("#{}: {}", name, outcome.descriptor),
+        None => outcome.descriptor.clone(),
+    };
+    if let Some(ref resolved) = outcome.resolved {
+        specification += &format!(" → {}", resolved);
+    }
+    if let Some(suffix) = outcome.mode.suffix() {
+        specification += &format!(" {}", suffix);
+    }
+    specification
+}
+
+/// A one-line summary of an outcome, like `3d6 + 2 = 13 (3d6 [6, 1, 4] + 2)`.
+pub fn summary(outcome: &RollOutcome) -> String {
+    format!("{} = {} ({})", specification(outcome), result(outcome), outcome.breakdown())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use roll::{roll_batch, roll_seeded, RollOptions};
+
+    fn outcome(s: &str) -> RollOutcome {
+        roll_seeded(s, 0, &RollOptions::default()).unwrap()
+    }
+
+    fn group(s: &str) -> Option<String> {
+        let outcomes = roll_batch(s, 0, &RollOptions::default()).unwrap();
+        group_result(&outcomes.iter().collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn results_show_checks() {
+        assert_eq!(result(&outcome("12 + 5 >= 15")), "17 PASS (+2)");
+        assert_eq!(result(&outcome("12 <= 10")), "12 FAIL (-2)");
+    }
+
+    #[test]
+    fn results_show_pools() {
+        let pool = outcome("8d10>=8");
+        assert_eq!(result(&pool), format!("{} {}", pool.outcome, if pool.outcome == 1 { "success" } else { "successes" }));
+    }
+
+    #[test]
+    fn repeated_rolls_are_totalled() {
+        let outcomes = roll_batch("3x 1d6 + 1", 0, &RollOptions::default()).unwrap();
+        let total: i32 = outcomes.iter().map(|outcome| outcome.outcome).sum();
+        assert_eq!(group("3x 1d6 + 1"), Some(total.to_string()));
+        assert_eq!(group("4x 10 vs 12; 2x 15 vs 12"), None);
+        assert_eq!(group("2x 15 vs 12"), Some("2 of 2 pass".to_string()));
+        let successes: i32 = roll_batch("3x 4d10>=8", 0, &RollOptions::default()).unwrap().iter()
+            .map(|outcome| outcome.outcome).sum();
+        let noun = if successes == 1 { "success" } else { "successes" };
+        assert_eq!(group("3x 4d10>=8"), Some(format!("{} {}", successes, noun)));
+    }
+
+    #[test]
+    fn different_rolls_are_not_totalled() {
+        assert_eq!(group("1d20 + 5; 1d8 + 3"), None);
+        assert_eq!(group("1d20 + 5; 1d20 + 5 crit"), None);
+    }
+
+    #[test]
+    fn the_fate_ladder_names_results() {
+        assert_eq!(fate_ladder(4), "Great (+4)");
+        assert_eq!(fate_ladder(0), "Mediocre (+0)");
+        assert_eq!(fate_ladder(-2), "Terrible (-2)");
+    }
+}
@@ -0,0 +1,1282 @@
+//! The GTK+ front end.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::mpsc::{self, TryRecvError};
+use std::thread;
+
+// GUI imports
+use gtk::*;
+use relm::{Relm, Widget, Update};
+
+// Logic imports
+use d20roll::analysis::{analyze, Analysis};
+use d20roll::export::{export_to_file, ExportFormat};
+use d20roll::format;
+use d20roll::history::History;
+use d20roll::hit_points::{self, Adjustment, ChangeKind, HitPoints};
+use d20roll::initiative::{Combatant, Tracker};
+use d20roll::rng::SeedSequence;
+use d20roll::roll::{definition, lazy_roll_batch, parse, statements, Definition, EvalOptions, Mode, RollError,
+                    RollOptions, RollOutcome, Span};
+use d20roll::settings::Settings;
+use d20roll::tables::{self, Table};
+
+/// The model keeps track of all the state of the program
+struct Model {
+    /// The async event resolution system
+    pub relm: Relm<Win>,
+    /// The current content of the text entry box
+    pub textentry_content: String,
+    /// All the rolls the program has computed, including ones saved from past sessions
+    pub rolls: History,
+    /// Where the seed for each roll comes from
+    pub seeds: SeedSequence,
+    /// Where the seeds for initiative come from, apart from the rolls so that replaying
+    /// the session's inputs gives the same dice
+    pub initiative_seeds: SeedSequence,
+    /// How rolls are evaluated
+    pub options: RollOptions,
+    /// Whether Fudge dice results are shown on the FATE ladder
+    pub fate_ladder: bool,
+    /// Each character's variables, by the character's name
+    pub profiles: BTreeMap<String, BTreeMap<String, i32>>,
+    /// The character whose variables are used in rolls, if any
+    pub profile: Option<String>,
+    /// The expression most recently submitted for rolling
+    pub last_spec: String,
+    /// Why the most recent roll failed, if it did
+    pub error: Option<RollError>,
+    /// The odds most recently calculated, and the expression they're for
+    pub analysis: Option<(String, Result<Analysis, RollError>)>,
+    /// Counts changes to the input, so only the preview for the latest one is shown
+    pub preview_id: u32,
+    /// The combatants in the current combat, and whose turn it is
+    pub initiative: Tracker,
+}
+
+/// All the actions available to the program
+#[derive(Msg, Debug)]
+enum Message {
+    /// Fired every time the input is changed
+    ChangeInput,
+    /// Fired a moment after the input is changed, to preview it if it hasn't changed since
+    ShowPreview(u32),
+    /// Fired when the odds for a preview have been worked out in the background, with
+    /// the preview's number, whether the input repeats its roll, and the odds if the
+    /// input can be rolled
+    FinishPreview(u32, bool, Option<Analysis>),
+    /// Fired when a roll is triggered - either by the "activate" event
+    /// or a click on the button
+    StartRoll,
+    /// Fired when a macro's button is clicked, to roll the macro with that name
+    RollMacro(String),
+    /// Fired when the async future for rolling an input completes, with an outcome
+    /// for each of its rolls
+    FinishRoll(Vec<RollOutcome>),
+    /// Fired when the async future for rolling an expression fails
+    RollFailed(RollError),
+    /// Fired when the user asks to export the history
+    Export,
+    /// Fired when the FATE ladder is switched on or off
+    ToggleFateLadder,
+    /// Fired when a different character is chosen
+    ChangeProfile,
+    /// Fired when a different mode is chosen for counting the dice
+    ChangeMode,
+    /// Fired when the user asks for the odds of the expression being entered
+    Analyze,
+    /// Fired when the odds asked for have been worked out in the background, with the
+    /// expression they're for
+    FinishAnalysis(String, Result<Analysis, RollError>),
+    /// Fired when the value to calculate the chance of rolling at least is changed
+    ChangeTarget,
+    /// Fired when the user adds a combatant to the initiative tracker
+    AddCombatant,
+    /// Fired when the user removes the selected combatant from the initiative tracker
+    RemoveCombatant,
+    /// Fired when the user asks to roll initiative for every combatant
+    RollInitiative,
+    /// Fired when the turn passes to the next combatant
+    NextTurn,
+    /// Fired when the turn goes back to the previous combatant
+    PreviousTurn,
+    /// Fired when the user applies the selected roll to the selected combatant's hit
+    /// points, as damage, healing or temporary hit points
+    ApplyRoll(ChangeKind),
+    /// Fired when the user undoes the last change to a combatant's hit points
+    UndoChange,
+    /// Fired when the application is closed/quit
+    Quit
+}
+
+/// Stores references to the retained state of the GUI.
+/// Because of how GTK+ works, it looks like we own the memory,
+/// but all the GTK+ widgets are actually owned by GTK+.
+struct Win {
+    /// The application state
+    model: Model,
+    /// The window containing the application's GUI
+    window: Window,
+    /// Chooses the character whose variables are used in rolls
+    profile_combo: ComboBoxText,
+    /// Chooses how the dice are counted, unless an expression says otherwise
+    mode_combo: ComboBoxText,
+    /// The input into which dice expressions can be entered
+    input: Entry,
+    /// Shows the range and average of the expression being entered
+    preview_label: Label,
+    /// Explains why the last roll failed, if it did
+    error_label: Label,
+    /// Shows the rolls so far, newest first
+    rolls_view: TreeView,
+    /// Data for the treeview that reports the result of dice rolls. Rolls made
+    /// together from one input are grouped under a row for the whole batch.
+    rolls_store: TreeStore,
+    /// Asks for the odds of the expression being entered, unless they're being worked out
+    odds_button: Button,
+    /// Summarizes the odds of the analyzed expression
+    odds_summary: Label,
+    /// The value to calculate the chance of rolling at least
+    odds_target: SpinButton,
+    /// The chance of rolling at least the target
+    odds_chance: Label,
+    /// Data for the treeview that lists the chance of each value
+    odds_store: ListStore,
+    /// The name of the next combatant to add
+    combatant_name: Entry,
+    /// The initiative modifier of the next combatant to add
+    combatant_modifier: SpinButton,
+    /// The tie-breaker of the next combatant to add
+    combatant_tie_breaker: SpinButton,
+    /// The maximum hit points of the next combatant to add, or 0 not to track them
+    combatant_hit_points: SpinButton,
+    /// Lists the combatants in turn order
+    initiative_view: TreeView,
+    /// Data for the treeview that lists the combatants
+    initiative_store: ListStore,
+    /// Shows the round and whose turn it is
+    round_label: Label,
+    /// The tags of damage the selected combatant resists, like `fire, cold`
+    resistances: Entry,
+    /// The tags of damage the selected combatant is vulnerable to
+    vulnerabilities: Entry,
+    /// Shows the latest changes to the combatants' hit points
+    hit_points_log: Label,
+}
+
+/// The Update trait allows the Relm API to work with the app
+impl Update for Win {
+    type Model = Model;
+    type ModelParam = ();
+    type Msg = Message;
+
+    // Create the inital model - the inital state of the application
+    fn model(relm: &Relm<Self>, _: Self::ModelParam) -> Self::Model {
+        let settings = Settings::load().unwrap_or_else(|e| {
+            eprintln!("d20roll: couldn't read the settings, using the defaults: {}", e);
+            Settings::default()
+        });
+        let rolls = open_history(&settings);
+        let seeds = match settings.seed {
+            Some(seed) => SeedSequence::new(seed),
+            None => SeedSequence::from_entropy(),
+        };
+        let eval = settings.eval_options();
+        let options = RollOptions { tables: Arc::new(open_tables(&eval)), eval };
+        Model {
+            relm: relm.clone(),
+            rolls,
+            initiative_seeds: seeds.side_sequence(),
+            seeds,
+            options,
+            fate_ladder: settings.fate_ladder,
+            profile: settings.profile.clone().filter(|profile| settings.profiles.contains_key(profile)),
+            profiles: settings.profiles,
+            textentry_content: String::new(),
+            last_spec: String::new(),
+            error: None,
+            analysis: None,
+            preview_id: 0,
+            initiative: open_initiative(),
+        }
+    }
+
+    // Update the model when a message is received
+    fn update(&mut self, event: Self::Msg) {
+        // These are set to true if these parts of the UI need to be refreshed.
+        let mut input_invalid = false;
+        let mut output_invalid = false;
+        let mut error_invalid = false;
+        let mut odds_invalid = false;
+        let mut initiative_invalid = false;
+
+        match event {
+            // When the Quit event fires, just end the program.
+            Message::Quit => gtk::main_quit(),
+            // When the Export event fires, ask where to and write the history out.
+            Message::Export => self.export_history(),
+            // When the ToggleFateLadder event fires, redisplay the results.
+            Message::ToggleFateLadder => {
+                self.model.fate_ladder = !self.model.fate_ladder;
+                output_invalid = true;
+            },
+            // When the ChangeProfile event fires, switch to the chosen character's variables.
+            Message::ChangeProfile => {
+                self.model.profile = self.profile_combo.get_active_id();
+                self.model.options.eval.variables = self.model.profile.as_ref()
+                    .and_then(|profile| self.model.profiles.get(profile))
+                    .cloned()
+                    .unwrap_or_default();
+                // The variables may change what the input rolls
+                self.refresh_preview();
+            },
+            // When the ChangeMode event fires, count the dice of later rolls that way.
+            Message::ChangeMode => {
+                self.model.options.eval.mode = self.mode_combo.get_active_id()
+                    .and_then(|id| Mode::all().iter().cloned().find(|mode| mode.name() == id))
+                    .unwrap_or(Mode::Normal);
+                self.refresh_preview();
+            },
+            // When the Analyze event fires, start working out the odds of the entered
+            // expression, or of the last one rolled if nothing is entered.
+            Message::Analyze => {
+                let spec = if self.model.textentry_content.trim().is_empty() {
+                    self.model.last_spec.clone()
+                } else {
+                    self.model.textentry_content.clone()
+                };
+                self.start_analysis(spec);
+            },
+            // When the FinishAnalysis event fires, show the odds and allow asking again.
+            Message::FinishAnalysis(spec, analysis) => {
+                self.odds_button.set_sensitive(true);
+                // Start the target at the mean, where its chance is most informative
+                if let Ok(ref analysis) = analysis {
+                    let distribution = &analysis.distribution;
+                    self.odds_target.set_range(f64::from(distribution.min()), f64::from(distribution.max()));
+                    self.odds_target.set_value(distribution.mean().round());
+                }
+                self.model.analysis = Some((spec, analysis));
+                odds_invalid = true;
+            },
+            // When the ChangeTarget event fires, recalculate the chance of reaching it.
+            Message::ChangeTarget => odds_invalid = true,
+            // When the AddCombatant event fires, add the combatant if they have a name.
+            Message::AddCombatant => {
+                let name = self.combatant_name.get_text().unwrap_or_default();
+                if !name.trim().is_empty() {
+                    let mut combatant = Combatant::new(name.trim(), self.combatant_modifier.get_value_as_int(),
+                        self.combatant_tie_breaker.get_value_as_int());
+                    let max = self.combatant_hit_points.get_value_as_int();
+                    if max > 0 {
+                        combatant.hit_points = Some(HitPoints::new(max));
+                    }
+                    self.model.initiative.add(combatant);
+                    self.combatant_name.set_text("");
+                    initiative_invalid = true;
+                }
+            },
+            // When the RemoveCombatant event fires, remove whichever combatant is selected.
+            Message::RemoveCombatant => {
+                if let Some((model, iter)) = self.initiative_view.get_selection().get_selected() {
+                    if let Some(path) = model.get_path(&iter) {
+                        self.model.initiative.remove(path.get_indices()[0] as usize);
+                        initiative_invalid = true;
+                    }
+                }
+            },
+            // When the RollInitiative event fires, roll for everyone and sort them into turn order.
+            Message::RollInitiative => {
+                let seed = self.model.initiative_seeds.next_seed();
+                if let Err(e) = self.model.initiative.roll(seed, &self.model.options) {
+                    self.error_label.set_markup(&format!("<span foreground=\"#c62828\">Couldn't roll initiative: {}</span>",
+                        escape_markup(&e.kind.to_string())));
+                    self.error_label.show();
+                }
+                initiative_invalid = true;
+            },
+            // When the NextTurn or PreviousTurn event fires, move the turn along.
+            Message::NextTurn => {
+                self.model.initiative.next_turn();
+                initiative_invalid = true;
+            },
+            Message::PreviousTurn => {
+                self.model.initiative.previous_turn();
+                initiative_invalid = true;
+            },
+            // When the ApplyRoll event fires, apply the selected roll, or the latest if
+            // none is selected, to the selected combatant, or whoever's turn it is.
+            Message::ApplyRoll(kind) => {
+                let mut adjustments = BTreeMap::new();
+                for &(entry, adjustment) in &[(&self.resistances, Adjustment::Resistant),
+                                                  (&self.vulnerabilities, Adjustment::Vulnerable)] {
+                    for tag in entry.get_text().unwrap_or_default().split(',').map(str::trim).filter(|tag| !tag.is_empty()) {
+                        adjustments.insert(tag.to_string(), adjustment);
+                    }
+                }
+                if let (Some(roll), Some(index)) = (self.selected_roll().cloned(), self.selected_combatant()) {
+                    if self.model.initiative.apply(index, &roll, kind, &adjustments).is_some() {
+                        initiative_invalid = true;
+                    } else {
+                        self.hit_points_log.set_text(&format!("{}'s hit points aren't being tracked",
+                            self.model.initiative.combatants()[index].name));
+                    }
+                }
+            },
+            // When the UndoChange event fires, put back the hit points from before the last change.
+            Message::UndoChange => {
+                if self.model.initiative.undo().is_some() {
+                    initiative_invalid = true;
+                }
+            },
+            // When the ChangeInput event fires, record the new value.
+            Message::ChangeInput => {
+                self.model.textentry_content = self.input.get_text().unwrap().clone();
+                // Once the failed expression is edited, the error no longer describes it.
+                if self.model.error.is_some() && self.model.textentry_content != self.model.last_spec {
+                    self.model.error = None;
+                    error_invalid = true;
+                }
+                input_invalid = true;
+                // Mistakes are marked straight away, but the preview waits for a pause in
+                // typing, since working it out can take a while.
+                self.mark_input();
+                self.model.preview_id = self.model.preview_id.wrapping_add(1);
+                let id = self.model.preview_id;
+                let stream = self.model.relm.stream().clone();
+                gtk::timeout_add(PREVIEW_DELAY, move || {
+                    stream.emit(Message::ShowPreview(id));
+                    Continue(false)
+                });
+            }
+            // When the ShowPreview event fires, preview the input if it hasn't changed since.
+            Message::ShowPreview(id) => if id == self.model.preview_id {
+                self.refresh_preview();
+            },
+            // When the FinishPreview event fires, show the preview if it's still for the latest input.
+            Message::FinishPreview(id, repeated, analysis) => if id == self.model.preview_id {
+                match analysis {
+                    Some(ref analysis) => {
+                        let prefix = if repeated { "Each roll: " } else { "" };
+                        self.preview_label.set_text(&format!("{}{}", prefix, preview_text(analysis)));
+                        self.preview_label.show();
+                    },
+                    None => self.preview_label.hide(),
+                }
+            },
+            // When the StartRoll event fires, spin off a future to do the rolling.
+            // A definition, like `@hit = d{miss=0,hit=1}`, names the dice instead.
+            Message::StartRoll => {
+                // Get the spec from the current model.
+                let spec = self.model.textentry_content.clone();
+                let error = match definition(&spec) {
+                    Some(Ok(definition)) => {
+                        self.define(definition);
+                        None
+                    },
+                    Some(error) => error.err(),
+                    None => {
+                        self.start_roll(spec.clone());
+                        None
+                    },
+                };
+                // Clear the text entry and any old error, unless the definition needs fixing.
+                match error {
+                    Some(_) => self.model.last_spec = spec,
+                    None => self.model.textentry_content = String::new(),
+                }
+                self.model.error = error;
+                input_invalid = true;
+                error_invalid = true;
+            },
+            // When the RollMacro event fires, roll the macro, leaving the text entry alone.
+            Message::RollMacro(name) => {
+                self.start_roll(format!("#{}", name));
+                self.model.error = None;
+                error_invalid = true;
+            },
+            // When the FinishRoll event fires, record the results.
+            Message::FinishRoll(outcomes) => {
+                // Every roll is kept even if saving fails, and the failure is reported once
+                let mut failure = None;
+                for outcome in outcomes {
+                    if let Err(e) = self.model.rolls.push(outcome) {
+                        failure = Some(e);
+                    }
+                }
+                if let Some(e) = failure {
+                    eprintln!("d20roll: couldn't save the roll history: {}", e);
+                }
+                output_invalid = true;
+            },
+            // When the RollFailed event fires, put the expression back so it can be fixed.
+            Message::RollFailed(error) => {
+                self.model.textentry_content = self.model.last_spec.clone();
+                self.model.error = Some(error);
+                input_invalid = true;
+                error_invalid = true;
+            },
+        };
+
+        // Set the input text to the recorded value from the model.
+        if input_invalid {
+            self.input.set_text(&self.model.textentry_content);
+        }
+
+        // Show the error, if any, and select the part of the input responsible for it.
+        if error_invalid {
+            match self.model.error {
+                Some(ref error) => {
+                    self.error_label.set_markup(&error_markup(&self.model.last_spec, error));
+                    self.error_label.show();
+                    let (start, end) = char_range(&self.model.last_spec, error.span);
+                    self.input.select_region(start, end);
+                },
+                None => self.error_label.hide(),
+            }
+        }
+
+        if output_invalid {
+            self.refresh_rolls();
+        }
+
+        if odds_invalid {
+            self.refresh_odds();
+        }
+
+        // Save the initiative tracker whenever it changes, so the combat survives a restart.
+        if initiative_invalid {
+            if let Err(e) = self.model.initiative.save() {
+                eprintln!("d20roll: couldn't save the initiative tracker: {}", e);
+            }
+            self.refresh_initiative();
+        }
+    }
+}
+
+impl Win {
+    /// Spin off a future to roll an expression.
+    fn start_roll(&mut self, spec: String) {
+        // Remember it, so it can be restored if it fails to roll.
+        self.model.last_spec = spec.clone();
+        // Start a future for the roll computation.
+        let future = lazy_roll_batch(spec, self.model.seeds.next_seed(), self.model.options.clone());
+        // Tell Relm to fire a FinishRoll event when the future is finished,
+        // or a RollFailed event if it fails
+        self.model.relm.connect_exec(future, Message::FinishRoll, Message::RollFailed);
+    }
+
+    /// Name a kind of die for the rest of the session, and save it to the settings.
+    /// It can still be rolled by name if it can't be saved.
+    fn define(&mut self, definition: Definition) {
+        let Definition { name, faces } = definition;
+        self.model.options.eval.dice.insert(name.to_string(), faces.clone());
+        if let Err(e) = Settings::load().and_then(|mut settings| settings.save_dice(name, faces)) {
+            eprintln!("d20roll: couldn't save `@{}` to the settings: {}", name, e);
+        }
+    }
+
+    /// Ask where to export the history, then write it there in the format
+    /// chosen by the file's extension or the selected filter.
+    fn export_history(&self) {
+        let dialog = FileChooserDialog::new(Some("Export History"), Some(&self.window), FileChooserAction::Save);
+        dialog.add_buttons(&[
+            ("_Cancel", ResponseType::Cancel.into()),
+            ("_Export", ResponseType::Accept.into()),
+        ]);
+        dialog.set_do_overwrite_confirmation(true);
+        dialog.set_current_name("rolls.csv");
+        for format in ExportFormat::all() {
+            let filter = FileFilter::new();
+            FileFilterExt::set_name(&filter, format.name());
+            filter.add_pattern(&format!("*.{}", format.extension()));
+            dialog.add_filter(&filter);
+        }
+
+        let response = dialog.run();
+        let path = dialog.get_filename();
+        let filter_name = dialog.get_filter().and_then(|filter| FileFilterExt::get_name(&filter));
+        dialog.destroy();
+
+        let accept: i32 = ResponseType::Accept.into();
+        let path = match path {
+            Some(ref path) if response == accept => path,
+            _ => return,
+        };
+        let format = ExportFormat::from_path(path)
+            .or_else(|| ExportFormat::all().iter().cloned()
+                .find(|format| filter_name.as_deref() == Some(format.name())))
+            .unwrap_or(ExportFormat::Csv);
+
+        if let Err(e) = export_to_file(&self.model.rolls, format, path) {
+            let message = format!("Couldn't export the history to {}: {}", path.display(), e);
+            let error = MessageDialog::new(Some(&self.window), DialogFlags::MODAL, MessageType::Error,
+                ButtonsType::Close, &message);
+            error.run();
+            error.destroy();
+        }
+    }
+
+    /// Mark the input as invalid, with the reason in its tooltip, if any of its
+    /// statements don't parse.
+    fn mark_input(&self) {
+        let style = self.input.get_style_context().unwrap();
+        let parsed = statements(&self.model.textentry_content).and_then(|statements| {
+            statements.iter().try_for_each(|statement| parse(statement.expression, &self.model.options).map(|_| ()))
+        });
+        match parsed {
+            Err(ref error) if !self.model.textentry_content.trim().is_empty() => {
+                style.add_class("error");
+                self.input.set_tooltip_text(Some(error.kind.to_string().as_str()));
+            },
+            _ => {
+                style.remove_class("error");
+                self.input.set_tooltip_text(None);
+            },
+        }
+    }
+
+    /// Work out the range and average of the input in the background, to be shown
+    /// under it by a FinishPreview event, or show nothing if it can't be rolled. Inputs
+    /// of several rolls are only previewed if they roll the same expression each time,
+    /// like `6x 4d6dl1`. Any preview still being worked out is forgotten.
+    fn refresh_preview(&mut self) {
+        self.model.preview_id = self.model.preview_id.wrapping_add(1);
+        let statements = statements(&self.model.textentry_content).unwrap_or_default();
+        let expression = match statements.first() {
+            Some(first) if statements.iter().all(|s| s.expression.trim() == first.expression.trim()) => first.expression,
+            _ => return self.preview_label.hide(),
+        };
+        let repeated = statements.len() > 1 || statements[0].times > 1;
+
+        // Working out the odds can take a while, so it's done off the main loop, which
+        // checks back until it's done
+        let (sender, receiver) = mpsc::channel();
+        let expression = expression.to_string();
+        let options = self.model.options.clone();
+        thread::spawn(move || sender.send(analyze(&expression, &options).ok()));
+        let id = self.model.preview_id;
+        let stream = self.model.relm.stream().clone();
+        gtk::timeout_add(ODDS_POLL, move || match receiver.try_recv() {
+            Ok(analysis) => {
+                stream.emit(Message::FinishPreview(id, repeated, analysis));
+                Continue(false)
+            },
+            Err(TryRecvError::Empty) => Continue(true),
+            Err(TryRecvError::Disconnected) => Continue(false),
+        });
+    }
+
+    /// Work out the odds of an expression in the background, to be shown by a
+    /// FinishAnalysis event. They can't be asked for again until they're shown.
+    fn start_analysis(&mut self, spec: String) {
+        self.odds_button.set_sensitive(false);
+        self.odds_summary.set_markup(&format!("<b>{}</b>\nCalculating…", escape_markup(&spec)));
+        self.odds_chance.set_text("");
+        self.odds_store.clear();
+
+        let (sender, receiver) = mpsc::channel();
+        let options = self.model.options.clone();
+        thread::spawn(move || {
+            let analysis = analyze(&spec, &options);
+            sender.send((spec, analysis))
+        });
+        let stream = self.model.relm.stream().clone();
+        let odds_button = self.odds_button.clone();
+        gtk::timeout_add(ODDS_POLL, move || match receiver.try_recv() {
+            Ok((spec, analysis)) => {
+                stream.emit(Message::FinishAnalysis(spec, analysis));
+                Continue(false)
+            },
+            Err(TryRecvError::Empty) => Continue(true),
+            // The odds couldn't be worked out, but can still be asked for again
+            Err(TryRecvError::Disconnected) => {
+                odds_button.set_sensitive(true);
+                Continue(false)
+            },
+        });
+    }
+
+    /// The roll selected in the history, or the latest roll if none is. A batch's row
+    /// isn't a roll of its own, so selecting it gives none.
+    fn selected_roll(&self) -> Option<&RollOutcome> {
+        match self.rolls_view.get_selection().get_selected() {
+            Some((model, iter)) => model.get_value(&iter, 7).get::<i32>()
+                .filter(|&index| index >= 0)
+                .and_then(|index| self.model.rolls.iter().nth(index as usize)),
+            None => self.model.rolls.last(),
+        }
+    }
+
+    /// The index of the combatant selected in the initiative tracker, or of whoever's
+    /// turn it is if none is.
+    fn selected_combatant(&self) -> Option<usize> {
+        match self.initiative_view.get_selection().get_selected() {
+            Some((model, iter)) => model.get_path(&iter).map(|path| path.get_indices()[0] as usize),
+            None => self.model.initiative.turn(),
+        }
+    }
+
+    /// Set the rolls store's content to that of the model's roll list.
+    fn refresh_rolls(&self) {
+        self.rolls_store.clear();
+
+        // Rolls made together from one input are next to each other in the history
+        let mut groups: Vec<Vec<(usize, &RollOutcome)>> = Vec::new();
+        for (index, roll) in self.model.rolls.iter().enumerate() {
+            match groups.last_mut() {
+                Some(ref mut group) if roll.batch.is_some() && group[0].1.batch == roll.batch => group.push((index, roll)),
+                _ => groups.push(vec![(index, roll)]),
+            }
+        }
+
+        for group in groups {
+            let batch = match group[0].1.batch {
+                Some(ref batch) if group.len() > 1 => batch,
+                // Insert the new value at the beginning
+                _ => {
+                    let i = self.rolls_store.prepend(None);
+                    self.set_roll(&i, group[0].0, group[0].1);
+                    continue;
+                },
+            };
+            // The batch's row shows its input and its result as a whole, if it has one
+            let i = self.rolls_store.prepend(None);
+            let rolls: Vec<&RollOutcome> = group.iter().map(|&(_, roll)| roll).collect();
+            let result = format::group_result(&rolls).map(|result| format!("<b>{}</b>", escape_markup(&result)));
+            let weight = if rolls.iter().any(|roll| roll.critical || roll.fumble) { WEIGHT_BOLD } else { WEIGHT_NORMAL };
+            self.rolls_store.set(&i,
+                &[0, 1, 2, 3, 5, 7],
+                &[&batch.input, &result.unwrap_or_default(), &format::group_breakdown(&rolls), &format::details(rolls[0]), &weight, &-1]
+                );
+            // Its rolls go underneath, in the order they were rolled
+            for (index, roll) in group {
+                let child = self.rolls_store.append(&i);
+                self.set_roll(&child, index, roll);
+            }
+        }
+
+        // The newest batch is opened, so its rolls can be seen straight away
+        if let Some(newest) = self.rolls_store.get_iter_first() {
+            if let Some(path) = self.rolls_store.get_path(&newest) {
+                self.rolls_view.expand_row(&path, false);
+            }
+        }
+    }
+
+    /// Fill in a row of the rolls store with a roll, the `index`th in the history.
+    fn set_roll(&self, i: &TreeIter, index: usize, roll: &RollOutcome) {
+        // Critical hits and fumbles stand out with an icon, in bold and in color
+        let (icon, color) = if roll.critical {
+            (Some("starred"), Some("#9a6700"))
+        } else if roll.fumble {
+            (Some("dialog-warning"), Some("#c62828"))
+        } else {
+            (None, None)
+        };
+        let weight = if roll.critical || roll.fumble { WEIGHT_BOLD } else { WEIGHT_NORMAL };
+        self.rolls_store.set(i, 
+            &[0,1,2,3,4,5,6,7],  // Insert into rows 0 through 7
+            &[&format::specification(roll), &result_markup(roll, self.model.fate_ladder), &roll.breakdown(), &format::details(roll), &icon, &weight, &color, &(index as i32)] // Insert the descriptor, the outcome, the breakdown, the tooltip, the styling and where it is in the history
+            );
+    }
+
+    /// Show the combatants in turn order, marking whose turn it is.
+    fn refresh_initiative(&self) {
+        self.initiative_store.clear();
+        let tracker = &self.model.initiative;
+        for (index, combatant) in tracker.combatants().iter().enumerate() {
+            let current = tracker.turn() == Some(index);
+            let icon = if current { Some("go-next") } else { None };
+            let initiative = match combatant.roll {
+                Some(ref roll) => format!("{} ({})", roll.outcome, roll.breakdown()),
+                None => "—".to_string(),
+            };
+            let hit_points = combatant.hit_points.map(hit_points::describe).unwrap_or_else(|| "—".to_string());
+            let weight = if current { WEIGHT_BOLD } else { WEIGHT_NORMAL };
+            let i = self.initiative_store.append();
+            self.initiative_store.set(&i,
+                &[0, 1, 2, 3, 4, 5, 6],
+                &[&icon, &combatant.name, &initiative, &format!("{:+}", combatant.modifier), &combatant.tie_breaker, &weight, &hit_points]
+                );
+        }
+        // The latest changes to hit points, newest first
+        let changes: Vec<String> = tracker.log().iter().rev().take(HIT_POINTS_LOG_LENGTH).map(|change| change.describe()).collect();
+        self.hit_points_log.set_text(&changes.join("\n"));
+        match tracker.current() {
+            Some(combatant) => self.round_label.set_markup(&format!("Round {}: <b>{}</b>'s turn",
+                tracker.round(), escape_markup(&combatant.name))),
+            None => self.round_label.set_text("Roll initiative to start the combat"),
+        }
+    }
+
+    /// Show the model's analysis in the odds panel.
+    fn refresh_odds(&self) {
+        self.odds_store.clear();
+        let (spec, analysis) = match self.model.analysis {
+            Some((ref spec, Ok(ref analysis))) => (spec, analysis),
+            Some((ref spec, Err(ref error))) => {
+                self.odds_summary.set_markup(&error_markup(spec, error));
+                self.odds_chance.set_text("");
+                return;
+            },
+            None => return,
+        };
+        let distribution = &analysis.distribution;
+
+        let mut summary = format!(
+            "<b>{}</b>\nMin {}  Max {}  Mean {:.2}  Variance {:.2} (σ {:.2})",
+            escape_markup(spec), distribution.min(), distribution.max(),
+            distribution.mean(), distribution.variance(), distribution.std_dev(),
+        );
+        if let Some(pass_chance) = analysis.pass_chance {
+            summary += &format!("\nChance to pass: {}", percent(pass_chance));
+        }
+        if !distribution.is_exact() {
+            summary += "\n<i>Estimated from many rolls</i>";
+        }
+        self.odds_summary.set_markup(&summary);
+
+        let target = self.odds_target.get_value_as_int();
+        self.odds_chance.set_text(&format!("P(X ≥ {}) = {}", target, percent(distribution.at_least(target))));
+
+        for (value, p) in distribution.iter() {
+            let i = self.odds_store.append();
+            self.odds_store.set(&i,
+                &[0, 1, 2],
+                &[&value, &percent(p), &percent(distribution.at_least(value))]
+                );
+        }
+    }
+}
+
+
+impl Widget for Win {
+    type Root = Window;
+
+    // Return the root widget
+    fn root(&self) -> Self::Root {
+        self.window.clone()
+    }
+
+    // Create view widgets
+    fn view(relm: &Relm<Self>, model: Self::Model) -> Self {
+        // Set up the top level window
+        let window = Window::new(WindowType::Toplevel);
+        window.set_title("d20roll - Rust Dice Roller");
+        window.set_border_width(10);
+        window.set_position(gtk::WindowPosition::Center);
+        window.set_default_size(300, 400);
+
+        // This box organizes the entry and the output in vertical order
+        let vbox = Box::new(Orientation::Vertical, 0);
+
+        // This menu bar holds the actions that aren't needed every roll
+        let menu_bar = MenuBar::new();
+        let file_item = MenuItem::new_with_mnemonic("_File");
+        let file_menu = Menu::new();
+        let export_item = MenuItem::new_with_mnemonic("_Export History…");
+        file_menu.append(&export_item);
+        file_item.set_submenu(Some(&file_menu));
+        menu_bar.append(&file_item);
+        let view_item = MenuItem::new_with_mnemonic("_View");
+        let view_menu = Menu::new();
+        let ladder_item = CheckMenuItem::new_with_mnemonic("_FATE Ladder");
+        ladder_item.set_active(model.fate_ladder);
+        view_menu.append(&ladder_item);
+        view_item.set_submenu(Some(&view_menu));
+        menu_bar.append(&view_item);
+        vbox.add(&menu_bar);
+
+        // This box organizes the input entry and the Roll button
+        let hbox = Box::new(Orientation::Horizontal, 0);
+        // It needs to fill all the available space.
+        hbox.set_hexpand(true);
+
+        // This dropdown chooses the character whose variables are used, like `@str_mod`
+        let profile_combo = ComboBoxText::new();
+        profile_combo.append(None, "No Character");
+        for name in model.profiles.keys() {
+            profile_combo.append(Some(name.as_str()), name);
+        }
+        if !profile_combo.set_active_id(model.profile.as_deref()) {
+            profile_combo.set_active(0);
+        }
+        hbox.add(&profile_combo);
+
+        // This input accepts user text input
+        let input = Entry::new();
+        // It needs to push the button to the minimum possible size
+        input.set_hexpand(true);
+        hbox.add(&input);
+
+        // This dropdown chooses how the dice are counted: rolled, averaged, maximized or
+        // rolled for a critical hit
+        let mode_combo = ComboBoxText::new();
+        for mode in Mode::all() {
+            mode_combo.append(Some(mode.name()), mode.name());
+        }
+        mode_combo.set_active_id(Some(Mode::Normal.name()));
+        mode_combo.set_tooltip_text("How the dice are counted. A suffix like `avg`, `max` or `crit` overrides this for one roll.");
+        hbox.add(&mode_combo);
+
+        // This button submits the user input
+        let button = Button::new_with_label("Roll");
+        // The session seed is enough to reproduce every roll in the session
+        button.set_tooltip_text(Some(format!("Session seed: {}", model.seeds.session_seed()).as_str()));
+        hbox.add(&button);
+
+        vbox.add(&hbox);
+
+        // This label previews the expression being entered. It's hidden until there is one.
+        let preview_label = Label::new(None);
+        preview_label.set_halign(Align::Start);
+        preview_label.get_style_context().unwrap().add_class("dim-label");
+        vbox.add(&preview_label);
+
+        // This label explains why a roll failed. It's hidden until one does.
+        let error_label = Label::new(None);
+        error_label.set_halign(Align::Start);
+        error_label.set_line_wrap(true);
+        vbox.add(&error_label);
+
+        // This store holds all the rolls to be displayed on the UI
+        // The next three columns style each row: an icon, a font weight and a color. The
+        // last is where the roll is in the history, or -1 for a batch's row.
+        let rolls_store = TreeStore::new(&[
+            Type::String, Type::String, Type::String, Type::String,
+            Type::String, Type::I32, Type::String, Type::I32,
+        ]);
+        // This view displays the rolls so far
+        let rolls_view = TreeView::new_with_model(&rolls_store);
+        // The view needs to fill the whole UI
+        rolls_view.set_hexpand(true);
+        rolls_view.set_vexpand(true);
+        // The headers need to be visible so it's clear what each column is
+        rolls_view.set_headers_visible(true);
+        // Hovering over a roll shows when and from which seed it was rolled
+        rolls_view.set_tooltip_column(3);
+
+        // This column displays the rolls specifications
+        let spec_column = TreeViewColumn::new();
+        let cell = CellRendererText::new();
+        spec_column.set_title("Specification");
+        spec_column.set_visible(true);
+        // Critical hits and fumbles are marked with an icon before the specification
+        let icon_cell = CellRendererPixbuf::new();
+        spec_column.pack_start(&icon_cell, false);
+        spec_column.add_attribute(&icon_cell, "icon-name", 4);
+        spec_column.pack_start(&cell, true);
+        // Associate this column with column 0 of the model
+        spec_column.add_attribute(&cell, "text", 0);
+        style_cell(&spec_column, &cell);
+        rolls_view.append_column(&spec_column);
+
+        // This column displays the rolls results
+        let result_column = TreeViewColumn::new();
+        let cell = CellRendererText::new();
+        result_column.set_title("Result");
+        result_column.set_visible(true);
+        result_column.pack_start(&cell, true);
+        // Associate this column with column 1 of the model
+        result_column.add_attribute(&cell, "markup", 1);
+        style_cell(&result_column, &cell);
+        rolls_view.append_column(&result_column);
+
+        // This column displays the individual dice behind each result
+        let breakdown_column = TreeViewColumn::new();
+        let cell = CellRendererText::new();
+        breakdown_column.set_title("Breakdown");
+        breakdown_column.set_visible(true);
+        breakdown_column.pack_start(&cell, true);
+        // Associate this column with column 2 of the model
+        breakdown_column.add_attribute(&cell, "text", 2);
+        style_cell(&breakdown_column, &cell);
+        rolls_view.append_column(&breakdown_column);
+
+        // This bar holds a button for each macro, so they can be rolled with a click
+        let macro_bar = Box::new(Orientation::Horizontal, 5);
+        for (name, expression) in &model.options.eval.macros {
+            let macro_button = Button::new_with_label(name);
+            macro_button.set_tooltip_text(Some(format!("#{}: {}", name, expression).as_str()));
+            macro_bar.add(&macro_button);
+            // Whenever a macro's button is clicked, the macro needs to be rolled
+            let name = name.clone();
+            connect!(relm, macro_button, connect_clicked(_), Message::RollMacro(name.clone()));
+        }
+        // It scrolls sideways if there are more macros than fit
+        let macro_scroll = ScrolledWindow::new(None, None);
+        macro_scroll.set_policy(PolicyType::Automatic, PolicyType::Never);
+        macro_scroll.add(&macro_bar);
+        vbox.add(&macro_scroll);
+
+        // This wrapper enables scrolling of the list
+        let label_container_scroll = ScrolledWindow::new(None, None);
+        label_container_scroll.set_hexpand(true);
+        label_container_scroll.set_vexpand(true);
+
+        label_container_scroll.add(&rolls_view);
+        vbox.add(&label_container_scroll);
+
+        // This expander holds the odds of an expression, out of the way until wanted
+        let odds_expander = Expander::new(Some("Odds"));
+        let odds_box = Box::new(Orientation::Vertical, 5);
+
+        // This button calculates the odds of the expression being entered
+        let odds_button = Button::new_with_label("Calculate Odds");
+        odds_button.set_halign(Align::Start);
+        odds_box.add(&odds_button);
+
+        // This label summarizes the distribution
+        let odds_summary = Label::new(None);
+        odds_summary.set_halign(Align::Start);
+        odds_summary.set_line_wrap(true);
+        odds_box.add(&odds_summary);
+
+        // This box asks for a value and shows the chance of rolling at least it
+        let target_box = Box::new(Orientation::Horizontal, 5);
+        target_box.add(&Label::new(Some("At least")));
+        let odds_target = SpinButton::new_with_range(0.0, 0.0, 1.0);
+        target_box.add(&odds_target);
+        let odds_chance = Label::new(None);
+        target_box.add(&odds_chance);
+        odds_box.add(&target_box);
+
+        // This store holds the chance of each value: the value, exactly it, and at least it
+        let odds_store = ListStore::new(&[Type::I32, Type::String, Type::String]);
+        let odds_view = TreeView::new_with_model(&odds_store);
+        odds_view.set_headers_visible(true);
+        for (i, title) in ["Value", "P(X = k)", "P(X ≥ k)"].iter().enumerate() {
+            let column = TreeViewColumn::new();
+            let cell = CellRendererText::new();
+            column.set_title(title);
+            column.pack_start(&cell, true);
+            column.add_attribute(&cell, "text", i as i32);
+            odds_view.append_column(&column);
+        }
+        let odds_scroll = ScrolledWindow::new(None, None);
+        odds_scroll.set_min_content_height(150);
+        odds_scroll.add(&odds_view);
+        odds_box.add(&odds_scroll);
+
+        odds_expander.add(&odds_box);
+        vbox.add(&odds_expander);
+
+        // This expander holds the initiative tracker, out of the way until wanted
+        let initiative_expander = Expander::new(Some("Initiative"));
+        let initiative_box = Box::new(Orientation::Vertical, 5);
+
+        // This box adds a combatant: their name, modifier, tie-breaker and hit points
+        let combatant_box = Box::new(Orientation::Horizontal, 5);
+        let combatant_name = Entry::new();
+        combatant_name.set_placeholder_text("Name");
+        combatant_name.set_hexpand(true);
+        combatant_box.add(&combatant_name);
+        combatant_box.add(&Label::new(Some("Modifier")));
+        let combatant_modifier = SpinButton::new_with_range(-20.0, 20.0, 1.0);
+        combatant_box.add(&combatant_modifier);
+        combatant_box.add(&Label::new(Some("Tie-breaker")));
+        let combatant_tie_breaker = SpinButton::new_with_range(0.0, 30.0, 1.0);
+        combatant_tie_breaker.set_tooltip_text("Settles equal rolls, higher first, like a Dexterity score");
+        combatant_box.add(&combatant_tie_breaker);
+        combatant_box.add(&Label::new(Some("HP")));
+        let combatant_hit_points = SpinButton::new_with_range(0.0, 9999.0, 1.0);
+        combatant_hit_points.set_tooltip_text("Maximum hit points, or 0 not to track them");
+        combatant_box.add(&combatant_hit_points);
+        let add_button = Button::new_with_label("Add");
+        combatant_box.add(&add_button);
+        initiative_box.add(&combatant_box);
+
+        // This store holds the combatants: a marker for whose turn it is, their name,
+        // initiative, modifier and tie-breaker, the weight of their row, and their hit points
+        let initiative_store = ListStore::new(&[
+            Type::String, Type::String, Type::String, Type::String, Type::I32, Type::I32, Type::String,
+        ]);
+        let initiative_view = TreeView::new_with_model(&initiative_store);
+        initiative_view.set_headers_visible(true);
+        let turn_column = TreeViewColumn::new();
+        let icon_cell = CellRendererPixbuf::new();
+        turn_column.pack_start(&icon_cell, false);
+        turn_column.add_attribute(&icon_cell, "icon-name", 0);
+        initiative_view.append_column(&turn_column);
+        for &(i, title) in &[(1, "Name"), (2, "Initiative"), (3, "Modifier"), (4, "Tie-breaker"), (6, "Hit points")] {
+            let column = TreeViewColumn::new();
+            let cell = CellRendererText::new();
+            column.set_title(title);
+            column.pack_start(&cell, true);
+            column.add_attribute(&cell, "text", i);
+            column.add_attribute(&cell, "weight", 5);
+            initiative_view.append_column(&column);
+        }
+        let initiative_scroll = ScrolledWindow::new(None, None);
+        initiative_scroll.set_min_content_height(120);
+        initiative_scroll.add(&initiative_view);
+        initiative_box.add(&initiative_scroll);
+
+        // This box holds the controls for the combat as a whole
+        let turn_box = Box::new(Orientation::Horizontal, 5);
+        let roll_initiative_button = Button::new_with_label("Roll Initiative");
+        turn_box.add(&roll_initiative_button);
+        let previous_button = Button::new_with_label("Previous");
+        turn_box.add(&previous_button);
+        let next_button = Button::new_with_label("Next");
+        turn_box.add(&next_button);
+        let remove_button = Button::new_with_label("Remove");
+        turn_box.add(&remove_button);
+        let round_label = Label::new(None);
+        round_label.set_hexpand(true);
+        round_label.set_halign(Align::End);
+        turn_box.add(&round_label);
+        initiative_box.add(&turn_box);
+
+        // This box applies the selected roll to the selected combatant's hit points
+        let hit_points_box = Box::new(Orientation::Horizontal, 5);
+        let resistances = Entry::new();
+        resistances.set_placeholder_text("Resists");
+        resistances.set_tooltip_text("Tags of damage the combatant takes half of, like `fire, cold`");
+        hit_points_box.add(&resistances);
+        let vulnerabilities = Entry::new();
+        vulnerabilities.set_placeholder_text("Vulnerable to");
+        vulnerabilities.set_tooltip_text("Tags of damage the combatant takes twice, like `radiant`");
+        hit_points_box.add(&vulnerabilities);
+        let damage_button = Button::new_with_label("Damage");
+        damage_button.set_tooltip_text("Apply the selected roll, or the latest, as damage, halving or doubling the damage \
+            of each tag the combatant resists or is vulnerable to");
+        hit_points_box.add(&damage_button);
+        let heal_button = Button::new_with_label("Heal");
+        hit_points_box.add(&heal_button);
+        let temporary_button = Button::new_with_label("Temp HP");
+        hit_points_box.add(&temporary_button);
+        let undo_button = Button::new_with_label("Undo");
+        hit_points_box.add(&undo_button);
+        initiative_box.add(&hit_points_box);
+        let hit_points_log = Label::new(None);
+        hit_points_log.set_halign(Align::Start);
+        initiative_box.add(&hit_points_log);
+
+        initiative_expander.add(&initiative_box);
+        vbox.add(&initiative_expander);
+
+        window.add(&vbox);
+
+        window.show_all();
+        preview_label.hide();
+        error_label.hide();
+        if model.options.eval.macros.is_empty() {
+            macro_scroll.hide();
+        }
+        if model.profiles.is_empty() {
+            profile_combo.hide();
+        }
+
+        // The delete event should quit the app
+        connect!(relm, window, connect_delete_event(_, _), return (Some(Message::Quit), Inhibit(false)));
+        // Whenever the input is changed, the model needs to be updated
+        connect!(relm, input, connect_changed(_), Message::ChangeInput);
+        // Whenever another character is chosen, their variables need to be used
+        connect!(relm, profile_combo, connect_changed(_), Message::ChangeProfile);
+        // Whenever another mode is chosen, later rolls need to count their dice that way
+        connect!(relm, mode_combo, connect_changed(_), Message::ChangeMode);
+        // Whenever the export menu item is chosen, the history needs to be exported
+        connect!(relm, export_item, connect_activate(_), Message::Export);
+        // Whenever the FATE ladder item is toggled, the results need to be redisplayed
+        connect!(relm, ladder_item, connect_toggled(_), Message::ToggleFateLadder);
+        // Whenever the Roll button is clicked, a roll needs to start
+        connect!(relm, button, connect_clicked(_), Message::StartRoll);
+        // Whenever the user hits "enter" or submits the input in another way, a roll needs to start
+        connect!(relm, input, connect_activate(_), Message::StartRoll);
+        // Whenever the Calculate Odds button is clicked, the odds need to be worked out
+        connect!(relm, odds_button, connect_clicked(_), Message::Analyze);
+        // Whenever the target changes, the chance of reaching it needs to be recalculated
+        connect!(relm, odds_target, connect_value_changed(_), Message::ChangeTarget);
+        // Whenever a combatant is added, by the button or by hitting "enter" on their
+        // name, they need to join the combat
+        connect!(relm, add_button, connect_clicked(_), Message::AddCombatant);
+        connect!(relm, combatant_name, connect_activate(_), Message::AddCombatant);
+        // Whenever the combat's controls are clicked, the tracker needs updating
+        connect!(relm, remove_button, connect_clicked(_), Message::RemoveCombatant);
+        connect!(relm, roll_initiative_button, connect_clicked(_), Message::RollInitiative);
+        connect!(relm, previous_button, connect_clicked(_), Message::PreviousTurn);
+        connect!(relm, next_button, connect_clicked(_), Message::NextTurn);
+        // Whenever a roll is applied to hit points, or undone, the tracker needs updating
+        connect!(relm, damage_button, connect_clicked(_), Message::ApplyRoll(ChangeKind::Damage));
+        connect!(relm, heal_button, connect_clicked(_), Message::ApplyRoll(ChangeKind::Healing));
+        connect!(relm, temporary_button, connect_clicked(_), Message::ApplyRoll(ChangeKind::Temporary));
+        connect!(relm, undo_button, connect_clicked(_), Message::UndoChange);
+
+        let win = Win {
+            model,
+            window,
+            profile_combo,
+            mode_combo,
+            input,
+            preview_label,
+            error_label,
+            rolls_view,
+            rolls_store,
+            odds_button,
+            odds_summary,
+            odds_target,
+            odds_chance,
+            odds_store,
+            combatant_name,
+            combatant_modifier,
+            combatant_tie_breaker,
+            combatant_hit_points,
+            initiative_view,
+            initiative_store,
+            round_label,
+            resistances,
+            vulnerabilities,
+            hit_points_log,
+        };
+        // Show the rolls saved from past sessions
+        win.refresh_rolls();
+        // Show the combat carried over from the last session, if any
+        win.refresh_initiative();
+        win
+    }
+}
+
+/// How long to wait after the input changes before previewing it, in milliseconds
+const PREVIEW_DELAY: u32 = 300;
+
+/// How often to check whether odds being worked out in the background are done, in
+/// milliseconds
+const ODDS_POLL: u32 = 50;
+
+/// How many of the latest changes to hit points are shown
+const HIT_POINTS_LOG_LENGTH: usize = 5;
+
+/// The Pango weights of normal and bold text
+const WEIGHT_NORMAL: i32 = 400;
+const WEIGHT_BOLD: i32 = 700;
+
+/// Style a cell of the rolls view with the weight and color of its row.
+fn style_cell(column: &TreeViewColumn, cell: &CellRendererText) {
+    column.add_attribute(cell, "weight", 5);
+    column.add_attribute(cell, "foreground", 6);
+}
+
+/// Open the saved roll history, falling back to one that isn't saved if that fails.
+fn open_history(settings: &Settings) -> History {
+    let path = match History::default_path() {
+        Some(path) => path,
+        None => return History::with_limit(settings.history_limit),
+    };
+    let history = History::open(&path, settings.history_limit).unwrap_or_else(|e| {
+        eprintln!("d20roll: couldn't load the roll history from {}: {}", path.display(), e);
+        History::with_limit(settings.history_limit)
+    });
+    if let Some(backup) = history.backup() {
+        eprintln!("d20roll: some of the roll history in {} couldn't be read; it was backed up to {}",
+            path.display(), backup.display());
+    }
+    history
+}
+
+/// Open the saved initiative tracker, falling back to one that isn't saved if that fails.
+fn open_initiative() -> Tracker {
+    let path = match Tracker::default_path() {
+        Some(path) => path,
+        None => return Tracker::new(),
+    };
+    Tracker::open(&path).unwrap_or_else(|e| {
+        eprintln!("d20roll: couldn't load the initiative tracker from {}: {}", path.display(), e);
+        Tracker::new()
+    })
+}
+
+/// Load the random tables, skipping any that can't be loaded.
+fn open_tables(options: &EvalOptions) -> BTreeMap<String, Table> {
+    let (tables, errors) = tables::load(options);
+    for e in errors {
+        eprintln!("d20roll: couldn't load a table: {}", e);
+    }
+    tables
+}
+
+/// Escape text so it can be safely included in Pango markup.
+fn escape_markup(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Render the Result column for an outcome. Pools are shown in bold so their success
+/// counts aren't mistaken for totals, in red if they botched or green if exceptional.
+/// Checks are shown in green if they passed and red if they failed. Fudge dice are
+/// shown on the FATE ladder if `fate_ladder` is set.
+fn result_markup(outcome: &RollOutcome, fate_ladder: bool) -> String {
+    let result = if fate_ladder && format::is_fate(outcome) {
+        format::with_check(outcome, format::fate_ladder(outcome.outcome))
+    } else {
+        format::result(outcome)
+    };
+    let mut markup = escape_markup(&result);
+
+    let good = match (outcome.check, outcome.pool) {
+        (Some(check), _) => Some(check.passed),
+        (None, Some(pool)) if pool.botch => Some(false),
+        (None, Some(pool)) if pool.exceptional => Some(true),
+        _ => None,
+    };
+    match good {
+        Some(true) => markup = format!("<span foreground=\"#2e7d32\">{}</span>", markup),
+        Some(false) => markup = format!("<span foreground=\"#c62828\">{}</span>", markup),
+        None => {},
+    }
+    if outcome.pool.is_some() {
+        markup = format!("<b>{}</b>", markup);
+    }
+    markup
+}
+
+/// Describe the range and average of an expression, like `Range 3–18, average 10.50`.
+fn preview_text(analysis: &Analysis) -> String {
+    let distribution = &analysis.distribution;
+    // Estimates only cover the values that came up, so they're marked as approximate
+    let about = if distribution.is_exact() { "" } else { "about " };
+    let mut text = format!("Range {}{}–{}, average {}{:.2}", about, distribution.min(), distribution.max(),
+        about, distribution.mean());
+    if let Some(pass_chance) = analysis.pass_chance {
+        text += &format!(", {} to pass", percent(pass_chance));
+    }
+    text
+}
+
+/// Format a probability as a percentage.
+fn percent(p: f64) -> String {
+    format!("{:.2}%", p * 100.0)
+}
+
+/// Render a failed expression with the part responsible for the error highlighted,
+/// followed by a description of the error.
+fn error_markup(spec: &str, error: &RollError) -> String {
+    let start = error.span.start.min(spec.len());
+    let end = error.span.end.min(spec.len());
+    // Empty spans point at the end of the input; they need something visible to highlight.
+    let highlighted = if start == end { "\u{2423}" } else { &spec[start..end] };
+    format!(
+        "{}<span background=\"#f4c7c3\" underline=\"error\">{}</span>{}  <span foreground=\"#c62828\">{}</span>",
+        escape_markup(&spec[..start]),
+        escape_markup(highlighted),
+        escape_markup(&spec[end..]),
+        escape_markup(&error.kind.to_string()),
+    )
+}
+
+/// Convert a byte span of a string into the character offsets GTK+ expects.
+fn char_range(s: &str, span: Span) -> (i32, i32) {
+    let start = s[..span.start.min(s.len())].chars().count();
+    let end = s[..span.end.min(s.len())].chars().count();
+    (start as i32, end as i32)
+}
+
+/// Open the main window and run until it's closed.
+pub fn run() {
+    Win::run(()).unwrap();
+}
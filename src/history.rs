@@ -0,0 +1,361 @@
+//! The record of rolls made during a session, optionally saved across sessions.
+//!
+//! Saved histories are kept in a journal: a file with one JSON encoded roll per
+//! line. Each roll is appended as it's made, so a crash can at worst leave a partial
+//! last line behind, which is skipped when the journal is read back. Once the journal
+//! grows to twice the history limit it's compacted by writing the retained rolls to a
+//! temporary file and renaming it over the journal. Any other line that can't be read,
+//! like one written by a newer version, is damage rather than a crash, so the journal
+//! is copied to a backup before it's ever rewritten without that line.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::slice;
+
+use serde_json;
+
+use paths;
+use roll::RollOutcome;
+
+/// Every roll made so far, oldest first.
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    rolls: Vec<RollOutcome>,
+    /// The most rolls to retain; older ones are forgotten
+    limit: Option<usize>,
+    /// The journal rolls are saved to, if any
+    journal: Option<Journal>,
+    /// Where the journal was backed up to, if it had lines that couldn't be read
+    backup: Option<PathBuf>,
+}
+
+/// The file a history is saved to
+#[derive(Debug, Clone)]
+struct Journal {
+    path: PathBuf,
+    /// How many rolls the file holds, including ones beyond the limit
+    entries: usize,
+}
+
+impl History {
+    /// An empty history that is never saved and never forgets a roll.
+    pub fn new() -> History {
+        History { rolls: Vec::new(), limit: None, journal: None, backup: None }
+    }
+
+    /// An empty history that is never saved and keeps only the last `limit` rolls.
+    pub fn with_limit(limit: usize) -> History {
+        History { rolls: Vec::new(), limit: Some(limit), journal: None, backup: None }
+    }
+
+    /// Where the history is saved by default, if there is anywhere to save it.
+    pub fn default_path() -> Option<PathBuf> {
+        paths::data_dir().map(|dir| dir.join("history.jsonl"))
+    }
+
+    /// Load the history saved in a journal, keeping only the last `limit` rolls, and
+    /// save every new roll to it. The journal is created if it doesn't exist.
+    ///
+    /// If any line but a partial last one can't be read, the journal is first copied
+    /// beside itself with a `.bak` extension; see `backup`.
+    pub fn open<P: Into<PathBuf>>(path: P, limit: usize) -> io::Result<History> {
+        let path = path.into();
+        let Contents { mut rolls, lines: entries, damaged, unterminated } = read_journal(&path)?;
+        let backup = if damaged > 0 {
+            let backup = path.with_extension("jsonl.bak");
+            fs::copy(&path, &backup)?;
+            Some(backup)
+        } else {
+            None
+        };
+        if rolls.len() > limit {
+            let excess = rolls.len() - limit;
+            rolls.drain(..excess);
+        }
+
+        let mut history = History {
+            rolls,
+            limit: Some(limit),
+            journal: Some(Journal { path, entries }),
+            backup,
+        };
+        // Rewriting the journal drops both old rolls and unreadable lines, and ends the
+        // last line, so that new rolls are never appended to the end of another line.
+        // Damaged lines are kept in the backup.
+        if entries > history.rolls.len() || unterminated {
+            history.compact()?;
+        }
+        Ok(history)
+    }
+
+    /// Record a new roll, saving it to the journal if there is one.
+    pub fn push(&mut self, outcome: RollOutcome) -> io::Result<()> {
+        let line = match self.journal {
+            Some(_) => Some(encode(&outcome)?),
+            None => None,
+        };
+        self.rolls.push(outcome);
+        if let Some(limit) = self.limit {
+            if self.rolls.len() > limit {
+                let excess = self.rolls.len() - limit;
+                self.rolls.drain(..excess);
+            }
+        }
+
+        let needs_compaction = match (self.journal.as_mut(), line) {
+            (Some(journal), Some(line)) => {
+                append(&journal.path, &line)?;
+                journal.entries += 1;
+                journal.entries >= self.rolls.len() * 2
+            },
+            _ => false,
+        };
+        if needs_compaction {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite the journal to hold only the retained rolls.
+    fn compact(&mut self) -> io::Result<()> {
+        let journal = match self.journal {
+            Some(ref mut journal) => journal,
+            None => return Ok(()),
+        };
+
+        if let Some(dir) = journal.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let temporary = journal.path.with_extension("jsonl.tmp");
+        {
+            let mut file = File::create(&temporary)?;
+            for roll in &self.rolls {
+                file.write_all(encode(roll)?.as_bytes())?;
+            }
+            file.sync_all()?;
+        }
+        fs::rename(&temporary, &journal.path)?;
+        sync_dir(&journal.path)?;
+        journal.entries = self.rolls.len();
+        Ok(())
+    }
+
+    /// Where the journal was copied when it was opened, because it had lines besides a
+    /// partial last one that couldn't be read, if it did.
+    pub fn backup(&self) -> Option<&Path> {
+        self.backup.as_deref()
+    }
+
+    /// Iterate over the rolls, oldest first.
+    pub fn iter(&self) -> slice::Iter<'_, RollOutcome> {
+        self.rolls.iter()
+    }
+
+    /// The most recent roll, if any.
+    pub fn last(&self) -> Option<&RollOutcome> {
+        self.rolls.last()
+    }
+
+    pub fn len(&self) -> usize {
+        self.rolls.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rolls.is_empty()
+    }
+
+    /// Forget every roll, including any saved ones.
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.rolls.clear();
+        self.compact()
+    }
+}
+
+impl<'a> IntoIterator for &'a History {
+    type Item = &'a RollOutcome;
+    type IntoIter = slice::Iter<'a, RollOutcome>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Encode a roll as a journal line, including the trailing newline.
+fn encode(outcome: &RollOutcome) -> io::Result<String> {
+    let mut line = serde_json::to_string(outcome)?;
+    line.push('\n');
+    Ok(line)
+}
+
+/// Append a line to the journal, creating it (and its directory) if needed.
+fn append(path: &Path, line: &str) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    // A single write keeps the line in one piece unless the system itself fails.
+    file.write_all(line.as_bytes())?;
+    file.sync_data()
+}
+
+/// Make sure the directory holding a file has recorded a rename into it, so a crash
+/// can't undo it.
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+/// Directories can't be opened to be synced elsewhere; renames are durable once done.
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// What was read back from a journal
+struct Contents {
+    rolls: Vec<RollOutcome>,
+    /// How many lines the journal holds, including ones that couldn't be read
+    lines: usize,
+    /// How many lines couldn't be read, not counting a partial last line
+    damaged: usize,
+    /// Whether the last line has no newline at its end, whether or not it could be read
+    unterminated: bool,
+}
+
+/// Read every roll in a journal. Lines that can't be decoded are skipped, and counted
+/// as damaged unless they're blank or they're a last line cut short by a crash, which
+/// has no newline at its end.
+fn read_journal(path: &Path) -> io::Result<Contents> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+
+    let unterminated = bytes.last().is_some_and(|&byte| byte != b'\n');
+    let mut contents = Contents { rolls: Vec::new(), lines: 0, damaged: 0, unterminated };
+    let mut lines = bytes.split(|&byte| byte == b'\n').peekable();
+    while let Some(line) = lines.next() {
+        let partial = lines.peek().is_none();
+        if partial && line.is_empty() {
+            break;
+        }
+        contents.lines += 1;
+        match serde_json::from_slice(line) {
+            Ok(roll) => contents.rolls.push(roll),
+            Err(_) if partial || line.iter().all(u8::is_ascii_whitespace) => {},
+            Err(_) => contents.damaged += 1,
+        }
+    }
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    use roll::{roll_seeded, RollOptions};
+
+    /// An empty directory for a test to keep its journal in
+    fn directory(test: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("d20roll-history-{}-{}", process::id(), test));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn outcome(seed: u64) -> RollOutcome {
+        roll_seeded("1d20 + 5", seed, &RollOptions::default()).unwrap()
+    }
+
+    fn seeds(history: &History) -> Vec<Option<u64>> {
+        history.iter().map(|outcome| outcome.seed).collect()
+    }
+
+    #[test]
+    fn rolls_are_saved_and_read_back() {
+        let path = directory("saved").join("history.jsonl");
+        let mut history = History::open(&path, 10).unwrap();
+        for seed in 0..3 {
+            history.push(outcome(seed)).unwrap();
+        }
+        let history = History::open(&path, 10).unwrap();
+        assert_eq!(seeds(&history), vec![Some(0), Some(1), Some(2)]);
+        assert_eq!(history.backup(), None);
+    }
+
+    #[test]
+    fn only_the_latest_rolls_are_kept() {
+        let path = directory("limit").join("history.jsonl");
+        let mut history = History::open(&path, 2).unwrap();
+        for seed in 0..5 {
+            history.push(outcome(seed)).unwrap();
+        }
+        assert_eq!(seeds(&history), vec![Some(3), Some(4)]);
+        assert_eq!(seeds(&History::open(&path, 2).unwrap()), vec![Some(3), Some(4)]);
+        // Compaction keeps the journal from growing past twice the limit
+        assert!(fs::read_to_string(&path).unwrap().lines().count() < 4);
+    }
+
+    #[test]
+    fn a_partial_last_line_is_dropped() {
+        let path = directory("partial").join("history.jsonl");
+        let mut journal = encode(&outcome(0)).unwrap();
+        let last = encode(&outcome(1)).unwrap();
+        journal.push_str(&last[..last.len() / 2]);
+        fs::write(&path, journal).unwrap();
+
+        let mut history = History::open(&path, 10).unwrap();
+        assert_eq!(seeds(&history), vec![Some(0)]);
+        assert_eq!(history.backup(), None);
+        assert!(!path.with_extension("jsonl.bak").exists());
+
+        // New rolls aren't appended to the partial line
+        history.push(outcome(2)).unwrap();
+        assert_eq!(seeds(&History::open(&path, 10).unwrap()), vec![Some(0), Some(2)]);
+    }
+
+    #[test]
+    fn a_whole_last_line_without_a_newline_is_ended() {
+        let path = directory("unterminated").join("history.jsonl");
+        let journal = encode(&outcome(0)).unwrap();
+        fs::write(&path, journal.trim_end()).unwrap();
+
+        let mut history = History::open(&path, 10).unwrap();
+        assert_eq!(seeds(&history), vec![Some(0)]);
+        assert!(fs::read_to_string(&path).unwrap().ends_with('\n'));
+        history.push(outcome(1)).unwrap();
+        assert_eq!(seeds(&History::open(&path, 10).unwrap()), vec![Some(0), Some(1)]);
+        assert_eq!(history.backup(), None);
+    }
+
+    #[test]
+    fn damaged_lines_are_backed_up() {
+        let path = directory("damaged").join("history.jsonl");
+        let journal = format!("{}not a roll\n{}", encode(&outcome(0)).unwrap(), encode(&outcome(1)).unwrap());
+        fs::write(&path, &journal).unwrap();
+
+        let history = History::open(&path, 10).unwrap();
+        assert_eq!(seeds(&history), vec![Some(0), Some(1)]);
+        let backup = path.with_extension("jsonl.bak");
+        assert_eq!(history.backup(), Some(backup.as_path()));
+        assert_eq!(fs::read_to_string(&backup).unwrap(), journal);
+        assert!(!fs::read_to_string(&path).unwrap().contains("not a roll"));
+    }
+
+    #[test]
+    fn clearing_empties_the_journal() {
+        let path = directory("clear").join("history.jsonl");
+        let mut history = History::open(&path, 10).unwrap();
+        history.push(outcome(0)).unwrap();
+        history.clear().unwrap();
+        assert!(history.is_empty());
+        assert!(History::open(&path, 10).unwrap().is_empty());
+    }
+}
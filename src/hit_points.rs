@@ -0,0 +1,179 @@
+//! Keeping track of how hurt each combatant is.
+//!
+//! Damage comes off temporary hit points first, then current hit points, which never
+//! drop below zero. Healing never raises current hit points above the maximum, and
+//! temporary hit points don't stack: a combatant keeps whichever is higher, what they
+//! had or what they're given. Every change is recorded so it can be undone.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+
+/// A combatant's hit points
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HitPoints {
+    pub current: i32,
+    pub max: i32,
+    /// Taken off before current hit points, and lost when they run out
+    #[serde(default)]
+    pub temporary: i32,
+}
+
+impl HitPoints {
+    /// Unhurt hit points with the given maximum
+    pub fn new(max: i32) -> HitPoints {
+        HitPoints { current: max, max, temporary: 0 }
+    }
+
+    /// The hit points after taking `amount` damage.
+    pub fn damaged(self, amount: i32) -> HitPoints {
+        let absorbed = amount.min(self.temporary);
+        HitPoints {
+            current: (self.current - (amount - absorbed)).max(0),
+            temporary: self.temporary - absorbed,
+            ..self
+        }
+    }
+
+    /// The hit points after healing `amount`.
+    pub fn healed(self, amount: i32) -> HitPoints {
+        HitPoints { current: self.current.saturating_add(amount).min(self.max).max(self.current), ..self }
+    }
+
+    /// The hit points after being given `amount` temporary hit points.
+    pub fn with_temporary(self, amount: i32) -> HitPoints {
+        HitPoints { temporary: self.temporary.max(amount), ..self }
+    }
+}
+
+/// What a roll does to a combatant's hit points
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Damage,
+    Healing,
+    /// Temporary hit points
+    Temporary,
+}
+
+/// How a combatant takes a kind of damage
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Adjustment {
+    /// The damage as rolled
+    Normal,
+    /// Half the damage, rounded down
+    Resistant,
+    /// Twice the damage
+    Vulnerable,
+}
+
+impl Adjustment {
+    /// The name shown to users, like `Resistant`
+    pub fn name(self) -> &'static str {
+        match self {
+            Adjustment::Normal => "Normal",
+            Adjustment::Resistant => "Resistant",
+            Adjustment::Vulnerable => "Vulnerable",
+        }
+    }
+
+    /// The damage taken from a roll of `amount`.
+    pub fn apply(self, amount: i32) -> i32 {
+        match self {
+            Adjustment::Normal => amount,
+            Adjustment::Resistant => amount / 2,
+            Adjustment::Vulnerable => amount.saturating_mul(2),
+        }
+    }
+}
+
+/// A change to a combatant's hit points, as recorded in the log
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Change {
+    /// The combatant whose hit points changed
+    pub combatant: u32,
+    /// The combatant's name at the time
+    pub name: String,
+    pub kind: ChangeKind,
+    /// How the combatant took each tag of the damage they didn't take as rolled, like
+    /// resisting the `fire` of `1d8[slashing] + 2d6[fire]`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub adjustments: BTreeMap<String, Adjustment>,
+    /// The descriptor of the roll applied, like `2d6 + 3`
+    pub descriptor: String,
+    /// The outcome of the roll applied
+    pub rolled: i32,
+    /// How much was applied, after any resistance or vulnerability
+    pub amount: i32,
+    /// The combatant's hit points before the change, which undoing it restores
+    pub before: HitPoints,
+    pub after: HitPoints,
+    /// When the change was made
+    pub timestamp: DateTime<Utc>,
+}
+
+impl Change {
+    /// Describe the change, like `Goblin took 7 damage (2d6[fire] + 3, fire resistant): 12 → 5`.
+    pub fn describe(&self) -> String {
+        let what = match self.kind {
+            ChangeKind::Damage => format!("took {} damage", self.amount),
+            ChangeKind::Healing => format!("healed {}", self.amount),
+            ChangeKind::Temporary => format!("gained {} temporary hit points", self.amount),
+        };
+        let how: String = self.adjustments.iter()
+            .map(|(tag, adjustment)| format!(", {} {}", tag, adjustment.name().to_lowercase()))
+            .collect();
+        format!("{} {} ({}{}): {} → {}", self.name, what, self.descriptor, how, describe(self.before), describe(self.after))
+    }
+}
+
+/// Describe hit points, like `12/20` or `12/20 +5` with temporary hit points.
+pub fn describe(hit_points: HitPoints) -> String {
+    if hit_points.temporary > 0 {
+        format!("{}/{} +{}", hit_points.current, hit_points.max, hit_points.temporary)
+    } else {
+        format!("{}/{}", hit_points.current, hit_points.max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn damage_comes_off_temporary_hit_points_first() {
+        let hit_points = HitPoints::new(20).with_temporary(5);
+        assert_eq!(hit_points.damaged(3), HitPoints { current: 20, max: 20, temporary: 2 });
+        assert_eq!(hit_points.damaged(8), HitPoints { current: 17, max: 20, temporary: 0 });
+        assert_eq!(hit_points.damaged(30), HitPoints { current: 0, max: 20, temporary: 0 });
+    }
+
+    #[test]
+    fn healing_stops_at_the_maximum() {
+        let hurt = HitPoints::new(20).damaged(12);
+        assert_eq!(hurt.healed(5).current, 13);
+        assert_eq!(hurt.healed(50).current, 20);
+        // Hit points above the maximum aren't healed down to it
+        assert_eq!(HitPoints { current: 25, max: 20, temporary: 0 }.healed(5).current, 25);
+    }
+
+    #[test]
+    fn temporary_hit_points_keep_the_higher_amount() {
+        assert_eq!(HitPoints::new(20).with_temporary(5).with_temporary(3).temporary, 5);
+        assert_eq!(HitPoints::new(20).with_temporary(3).with_temporary(5).temporary, 5);
+    }
+
+    #[test]
+    fn adjustments() {
+        assert_eq!(Adjustment::Resistant.apply(7), 3);
+        assert_eq!(Adjustment::Vulnerable.apply(7), 14);
+        assert_eq!(Adjustment::Normal.apply(7), 7);
+    }
+
+    #[test]
+    fn descriptions() {
+        assert_eq!(describe(HitPoints::new(20).damaged(8)), "12/20");
+        assert_eq!(describe(HitPoints::new(20).with_temporary(5)), "20/20 +5");
+    }
+}
@@ -0,0 +1,414 @@
+//! Tracking turn order in combat.
+//!
+//! Each combatant rolls `1d20` plus their modifier, and goes in order from the
+//! highest roll down. Equal rolls are settled by the combatants' tie-breakers, like
+//! their Dexterity scores, and then by their modifiers. The tracker also keeps each
+//! combatant's hit points, with a log of every change so they can be undone. It's
+//! saved as JSON so a combat can carry on in the next session.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+use serde_json;
+
+use hit_points::{Adjustment, Change, ChangeKind, HitPoints};
+use paths;
+use rng::SeedSequence;
+use roll::{roll_seeded, Mode, RollError, RollOptions, RollOutcome};
+
+/// Someone taking part in a combat
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Combatant {
+    /// Tells combatants apart, even if they share a name. Given by the tracker.
+    #[serde(default)]
+    pub id: u32,
+    pub name: String,
+    /// Added to the combatant's initiative roll
+    pub modifier: i32,
+    /// Settles equal initiatives, higher first, like a Dexterity score
+    pub tie_breaker: i32,
+    /// The combatant's initiative roll, once initiative has been rolled
+    #[serde(default)]
+    pub roll: Option<RollOutcome>,
+    /// The combatant's hit points, if they're being tracked
+    #[serde(default)]
+    pub hit_points: Option<HitPoints>,
+}
+
+impl Combatant {
+    pub fn new(name: &str, modifier: i32, tie_breaker: i32) -> Combatant {
+        Combatant { id: 0, name: name.to_string(), modifier, tie_breaker, roll: None, hit_points: None }
+    }
+
+    /// The expression the combatant rolls for initiative, like `1d20 + 3`.
+    pub fn expression(&self) -> String {
+        match self.modifier {
+            0 => "1d20".to_string(),
+            modifier if modifier < 0 => format!("1d20 - {}", -i64::from(modifier)),
+            modifier => format!("1d20 + {}", modifier),
+        }
+    }
+
+    /// The combatant's initiative, once it's been rolled
+    pub fn initiative(&self) -> Option<i32> {
+        self.roll.as_ref().map(|roll| roll.outcome)
+    }
+}
+
+/// The combatants in a combat, in turn order once initiative is rolled, and whose
+/// turn it is.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Tracker {
+    combatants: Vec<Combatant>,
+    /// The index of the combatant whose turn it is
+    turn: usize,
+    /// The round of the combat, counting from 1, or 0 if it hasn't started
+    round: u32,
+    /// The id given to the last combatant added
+    #[serde(default)]
+    last_id: u32,
+    /// Every change to the combatants' hit points, oldest first
+    #[serde(default)]
+    log: Vec<Change>,
+    /// The file the tracker is saved to, if any
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+impl Tracker {
+    /// An empty tracker that is never saved.
+    pub fn new() -> Tracker {
+        Tracker::default()
+    }
+
+    /// Where the tracker is saved by default, if there is anywhere to save it.
+    pub fn default_path() -> Option<PathBuf> {
+        paths::data_dir().map(|dir| dir.join("initiative.json"))
+    }
+
+    /// Load the tracker saved in a file, and save it there from now on. The tracker
+    /// starts empty if the file doesn't exist.
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<Tracker> {
+        let path = path.into();
+        let mut tracker = match File::open(&path) {
+            Ok(file) => serde_json::from_reader(file).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Tracker::new(),
+            Err(e) => return Err(e),
+        };
+        // Trackers saved before combatants had ids give them one now
+        for index in 0..tracker.combatants.len() {
+            if tracker.combatants[index].id == 0 {
+                tracker.last_id += 1;
+                tracker.combatants[index].id = tracker.last_id;
+            }
+        }
+        tracker.path = Some(path);
+        Ok(tracker)
+    }
+
+    /// Save the tracker to its file, if it has one. The file is replaced in one step,
+    /// so a crash can't leave it half written.
+    pub fn save(&self) -> io::Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let temporary = path.with_extension("json.tmp");
+        {
+            let mut file = File::create(&temporary)?;
+            file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+            file.sync_all()?;
+        }
+        fs::rename(&temporary, path)
+    }
+
+    /// The file the tracker is saved to, if any
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Every combatant, in turn order once initiative is rolled
+    pub fn combatants(&self) -> &[Combatant] {
+        &self.combatants
+    }
+
+    /// The round of the combat, counting from 1, or 0 if it hasn't started
+    pub fn round(&self) -> u32 {
+        self.round
+    }
+
+    /// The index of the combatant whose turn it is, if the combat has started
+    pub fn turn(&self) -> Option<usize> {
+        if self.round > 0 && self.turn < self.combatants.len() { Some(self.turn) } else { None }
+    }
+
+    /// The combatant whose turn it is, if the combat has started
+    pub fn current(&self) -> Option<&Combatant> {
+        self.turn().map(|turn| &self.combatants[turn])
+    }
+
+    /// Add a combatant at the end of the turn order, giving them a new id. They have
+    /// no initiative until it's rolled again.
+    pub fn add(&mut self, mut combatant: Combatant) -> u32 {
+        self.last_id += 1;
+        combatant.id = self.last_id;
+        self.combatants.push(combatant);
+        self.last_id
+    }
+
+    /// Remove a combatant, keeping the turn with whoever has it. If it was the removed
+    /// combatant's turn, it passes to the next in order.
+    pub fn remove(&mut self, index: usize) {
+        if index >= self.combatants.len() {
+            return;
+        }
+        self.combatants.remove(index);
+        if index < self.turn {
+            self.turn -= 1;
+        }
+        if self.turn >= self.combatants.len() {
+            self.turn = 0;
+        }
+    }
+
+    /// Remove every combatant, ending the combat and forgetting the log.
+    pub fn clear(&mut self) {
+        self.combatants.clear();
+        self.log.clear();
+        self.turn = 0;
+        self.round = 0;
+    }
+
+    /// Every change to the combatants' hit points, oldest first
+    pub fn log(&self) -> &[Change] {
+        &self.log
+    }
+
+    /// Apply a roll to a combatant's hit points as damage, healing or temporary hit
+    /// points. Damage of each tag the combatant resists or is vulnerable to, like the
+    /// `fire` of `1d8[slashing] + 2d6[fire]`, is adjusted on its own, and the rest of
+    /// the roll, tagged or not, is taken as rolled. Rolls below zero count as zero.
+    /// Returns the change, or `None` if the combatant's hit points aren't being tracked.
+    pub fn apply(&mut self, index: usize, outcome: &RollOutcome, kind: ChangeKind,
+                 adjustments: &BTreeMap<String, Adjustment>) -> Option<&Change> {
+        let combatant = self.combatants.get_mut(index)?;
+        let before = combatant.hit_points?;
+        let rolled = outcome.outcome.max(0);
+        // Only the adjustments of tags the roll has, and that change anything, are recorded
+        let adjustments: BTreeMap<String, Adjustment> = match kind {
+            ChangeKind::Damage => outcome.subtotals.iter()
+                .filter_map(|subtotal| adjustments.get(&subtotal.tag).map(|&adjustment| (subtotal.tag.clone(), adjustment)))
+                .filter(|&(_, adjustment)| adjustment != Adjustment::Normal)
+                .collect(),
+            _ => BTreeMap::new(),
+        };
+        let (amount, after) = match kind {
+            ChangeKind::Damage => {
+                let amount = outcome.subtotals.iter().fold(outcome.outcome, |amount, subtotal| {
+                    match adjustments.get(&subtotal.tag) {
+                        Some(adjustment) => amount.saturating_sub(subtotal.total).saturating_add(adjustment.apply(subtotal.total)),
+                        None => amount,
+                    }
+                }).max(0);
+                (amount, before.damaged(amount))
+            },
+            ChangeKind::Healing => (rolled, before.healed(rolled)),
+            ChangeKind::Temporary => (rolled, before.with_temporary(rolled)),
+        };
+        combatant.hit_points = Some(after);
+        self.log.push(Change {
+            combatant: combatant.id,
+            name: combatant.name.clone(),
+            kind,
+            adjustments,
+            descriptor: outcome.descriptor.clone(),
+            rolled,
+            amount,
+            before,
+            after,
+            timestamp: Utc::now(),
+        });
+        self.log.last()
+    }
+
+    /// Undo the last change to a combatant's hit points, restoring what they were
+    /// before it. Returns the change undone, if there was one. Changes to combatants
+    /// who have since been removed are simply forgotten.
+    pub fn undo(&mut self) -> Option<Change> {
+        let change = self.log.pop()?;
+        if let Some(combatant) = self.combatants.iter_mut().find(|combatant| combatant.id == change.combatant) {
+            combatant.hit_points = Some(change.before);
+        }
+        Some(change)
+    }
+
+    /// Roll initiative for every combatant, put them in turn order, and start the
+    /// first round. Each combatant's roll draws its seed from a `SeedSequence` started
+    /// from `seed`. Initiative is always rolled normally, whatever mode `options` count
+    /// damage in.
+    pub fn roll(&mut self, seed: u64, options: &RollOptions) -> Result<(), RollError> {
+        let mut options = options.clone();
+        options.eval.mode = Mode::Normal;
+        let mut seeds = SeedSequence::new(seed);
+        for combatant in &mut self.combatants {
+            combatant.roll = Some(roll_seeded(&combatant.expression(), seeds.next_seed(), &options)?);
+        }
+        // Highest first; sorting is stable, so complete ties stay in the order added
+        self.combatants.sort_by(|a, b| {
+            (b.initiative(), b.tie_breaker, b.modifier).cmp(&(a.initiative(), a.tie_breaker, a.modifier))
+        });
+        self.turn = 0;
+        self.round = if self.combatants.is_empty() { 0 } else { 1 };
+        Ok(())
+    }
+
+    /// Pass the turn to the next combatant, starting a new round after the last. Starts
+    /// the combat if it hasn't started.
+    pub fn next_turn(&mut self) {
+        if self.combatants.is_empty() {
+            return;
+        }
+        if self.round == 0 {
+            self.round = 1;
+            self.turn = 0;
+        } else if self.turn + 1 >= self.combatants.len() {
+            self.round += 1;
+            self.turn = 0;
+        } else {
+            self.turn += 1;
+        }
+    }
+
+    /// Give the turn back to the previous combatant, going back a round from the
+    /// first. Does nothing on the first turn of the combat.
+    pub fn previous_turn(&mut self) {
+        if self.combatants.is_empty() || self.round == 0 {
+            return;
+        }
+        if self.turn > 0 {
+            self.turn -= 1;
+        } else if self.round > 1 {
+            self.round -= 1;
+            self.turn = self.combatants.len() - 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use roll::roll_seeded;
+
+    fn tracker(combatants: &[(&str, i32, i32)]) -> Tracker {
+        let mut tracker = Tracker::new();
+        for &(name, modifier, tie_breaker) in combatants {
+            tracker.add(Combatant::new(name, modifier, tie_breaker));
+        }
+        tracker
+    }
+
+    fn names(tracker: &Tracker) -> Vec<&str> {
+        tracker.combatants().iter().map(|combatant| combatant.name.as_str()).collect()
+    }
+
+    #[test]
+    fn expressions_include_the_modifier() {
+        assert_eq!(Combatant::new("a", 0, 0).expression(), "1d20");
+        assert_eq!(Combatant::new("a", 3, 0).expression(), "1d20 + 3");
+        assert_eq!(Combatant::new("a", -2, 0).expression(), "1d20 - 2");
+    }
+
+    #[test]
+    fn initiative_puts_combatants_in_order() {
+        let mut tracker = tracker(&[("Goblin", 2, 14), ("Thorin", 1, 12), ("Elara", 4, 18)]);
+        tracker.roll(7, &RollOptions::default()).unwrap();
+        let order: Vec<(Option<i32>, i32)> = tracker.combatants().iter()
+            .map(|combatant| (combatant.initiative(), combatant.tie_breaker)).collect();
+        let mut sorted = order.clone();
+        sorted.sort_by(|a, b| b.cmp(a));
+        assert_eq!(order, sorted);
+        assert_eq!((tracker.round(), tracker.turn()), (1, Some(0)));
+    }
+
+    #[test]
+    fn ties_are_settled_by_the_tie_breaker() {
+        // Find a seed that rolls the same for both, which about one in twenty do
+        let tied = (0..1000).map(|seed| {
+            let mut tracker = tracker(&[("Slow", 0, 8), ("Fast", 0, 18)]);
+            tracker.roll(seed, &RollOptions::default()).unwrap();
+            tracker
+        }).find(|tracker| tracker.combatants()[0].initiative() == tracker.combatants()[1].initiative()).unwrap();
+        assert_eq!(names(&tied), vec!["Fast", "Slow"]);
+    }
+
+    #[test]
+    fn initiative_ignores_the_damage_mode() {
+        let mut normal = tracker(&[("Goblin", 2, 14), ("Thorin", 1, 12)]);
+        let mut critical = normal.clone();
+        normal.roll(7, &RollOptions::default()).unwrap();
+        let mut options = RollOptions::default();
+        options.eval.mode = Mode::Critical;
+        critical.roll(7, &options).unwrap();
+        assert_eq!(names(&normal), names(&critical));
+        for (a, b) in normal.combatants().iter().zip(critical.combatants()) {
+            assert_eq!(a.initiative(), b.initiative());
+            assert_eq!(b.roll.as_ref().unwrap().mode, Mode::Normal);
+        }
+    }
+
+    #[test]
+    fn turns_go_round() {
+        let mut tracker = tracker(&[("a", 0, 0), ("b", 0, 0)]);
+        assert_eq!(tracker.turn(), None);
+        tracker.next_turn();
+        assert_eq!((tracker.round(), tracker.turn()), (1, Some(0)));
+        tracker.next_turn();
+        tracker.next_turn();
+        assert_eq!((tracker.round(), tracker.turn()), (2, Some(0)));
+        tracker.previous_turn();
+        assert_eq!((tracker.round(), tracker.turn()), (1, Some(1)));
+        tracker.remove(1);
+        assert_eq!(tracker.turn(), Some(0));
+    }
+
+    #[test]
+    fn hit_point_changes_can_be_undone() {
+        let mut tracker = tracker(&[("Goblin", 2, 14)]);
+        tracker.combatants[0].hit_points = Some(HitPoints::new(12));
+        let damage = roll_seeded("7", 0, &RollOptions::default()).unwrap();
+        let change = tracker.apply(0, &damage, ChangeKind::Damage, &BTreeMap::new()).unwrap().clone();
+        assert_eq!((change.rolled, change.amount), (7, 7));
+        assert_eq!(tracker.combatants()[0].hit_points.unwrap().current, 5);
+        assert_eq!(change.describe(), "Goblin took 7 damage (7): 12/12 → 5/12");
+        tracker.undo();
+        assert_eq!(tracker.combatants()[0].hit_points, Some(HitPoints::new(12)));
+        assert!(tracker.log().is_empty());
+    }
+
+    #[test]
+    fn damage_is_adjusted_by_its_tags() {
+        let mut tracker = tracker(&[("Fire Elemental", 1, 17)]);
+        tracker.combatants[0].hit_points = Some(HitPoints::new(50));
+        let mut adjustments = BTreeMap::new();
+        adjustments.insert("fire".to_string(), Adjustment::Resistant);
+        adjustments.insert("cold".to_string(), Adjustment::Vulnerable);
+        adjustments.insert("acid".to_string(), Adjustment::Resistant);
+        // 5 slashing as rolled, half of 7 fire, twice 3 cold, and 2 untagged as rolled
+        let damage = roll_seeded("5[slashing] + 7[fire] + 3[cold] + 2", 0, &RollOptions::default()).unwrap();
+        let change = tracker.apply(0, &damage, ChangeKind::Damage, &adjustments).unwrap().clone();
+        assert_eq!((change.rolled, change.amount), (17, 16));
+        assert_eq!(change.adjustments.len(), 2);
+        assert_eq!(change.describe(),
+            "Fire Elemental took 16 damage (5[slashing] + 7[fire] + 3[cold] + 2, cold vulnerable, fire resistant): 50/50 → 34/50");
+        // Healing isn't adjusted
+        let healing = roll_seeded("7[fire]", 0, &RollOptions::default()).unwrap();
+        let change = tracker.apply(0, &healing, ChangeKind::Healing, &adjustments).unwrap();
+        assert_eq!((change.amount, change.adjustments.is_empty()), (7, true));
+    }
+}
@@ -0,0 +1,34 @@
+//! The dice rolling engine behind d20roll.
+//!
+//! Expressions are written in "d20 system" notation: `NdM` rolls N dice with M
+//! sides, and can be combined with integer arithmetic and parentheses, like
+//! `(2d20 + 4) / 2 - (1d4 * 2)`. None of this depends on GTK+; the graphical front
+//! end lives in the binary and is only built with the `gui` feature.
+
+// The chrono crate provides timestamps for rolls
+extern crate chrono;
+// The csv crate provides CSV export
+extern crate csv;
+// The futures crate provides functionality for creating asyncronous functions
+extern crate futures;
+// The rand crate provides random numbers for rolling dice
+extern crate rand;
+// The serde crates provide serialization for saving rolls and settings
+extern crate serde;
+#[macro_use] extern crate serde_derive;
+extern crate serde_json;
+// The toml crate provides the format of the settings file
+extern crate toml;
+
+pub mod analysis;
+pub mod dice;
+pub mod export;
+pub mod format;
+pub mod history;
+pub mod hit_points;
+pub mod initiative;
+pub mod paths;
+pub mod rng;
+pub mod roll;
+pub mod settings;
+pub mod tables;
@@ -0,0 +1,24 @@
+//! Where d20roll keeps its files, following the XDG base directory specification.
+
+use std::env;
+use std::path::PathBuf;
+
+/// Resolve an XDG base directory: the environment variable if it's set to an absolute
+/// path, otherwise the given fallback under the home directory.
+fn xdg_dir(variable: &str, fallback: &str) -> Option<PathBuf> {
+    match env::var_os(variable).map(PathBuf::from) {
+        Some(ref dir) if dir.is_absolute() => Some(dir.join("d20roll")),
+        _ => env::var_os("HOME").map(|home| PathBuf::from(home).join(fallback).join("d20roll")),
+    }
+}
+
+/// The directory for data d20roll accumulates, like the roll history.
+/// Usually `~/.local/share/d20roll`.
+pub fn data_dir() -> Option<PathBuf> {
+    xdg_dir("XDG_DATA_HOME", ".local/share")
+}
+
+/// The directory for the user's settings. Usually `~/.config/d20roll`.
+pub fn config_dir() -> Option<PathBuf> {
+    xdg_dir("XDG_CONFIG_HOME", ".config")
+}
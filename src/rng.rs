@@ -0,0 +1,95 @@
+//! Seeded random number generation, so that rolls can be reproduced.
+//!
+//! Every seeded roll gets its own seed, which is recorded on its outcome. Within a
+//! session the roll seeds are drawn from a [`SeedSequence`], itself seeded by a
+//! session seed, so a session seed and the list of expressions rolled are enough to
+//! re-derive the whole session.
+
+use rand::{self, Rng, SeedableRng};
+use rand::prng::ChaChaRng;
+
+/// The generator used for seeded rolls. For a given seed its output never changes,
+/// which is what makes replays possible.
+pub type SeededRng = ChaChaRng;
+
+/// Create the generator for a seed.
+pub fn seeded(seed: u64) -> SeededRng {
+    let mut bytes = [0u8; 32];
+    for (i, byte) in bytes.iter_mut().take(8).enumerate() {
+        *byte = (seed >> (8 * i)) as u8;
+    }
+    ChaChaRng::from_seed(bytes)
+}
+
+/// A fresh seed from the operating system's entropy.
+pub fn random_seed() -> u64 {
+    rand::random()
+}
+
+/// Hands out a seed for each roll in a session. The same session seed always hands
+/// out the same roll seeds, in the same order.
+#[derive(Debug, Clone)]
+pub struct SeedSequence {
+    session_seed: u64,
+    rng: SeededRng,
+}
+
+impl SeedSequence {
+    pub fn new(session_seed: u64) -> SeedSequence {
+        SeedSequence { session_seed, rng: seeded(session_seed) }
+    }
+
+    /// A sequence with a random session seed.
+    pub fn from_entropy() -> SeedSequence {
+        SeedSequence::new(random_seed())
+    }
+
+    /// The seed the sequence was started from
+    pub fn session_seed(&self) -> u64 {
+        self.session_seed
+    }
+
+    /// The seed for the next roll.
+    pub fn next_seed(&mut self) -> u64 {
+        self.rng.gen()
+    }
+
+    /// A sequence for rolls made apart from the session's inputs, like initiative, so
+    /// they don't shift the seeds the inputs get. It's started from the same session
+    /// seed, so it hands out the same seeds for the same session seed, but none of the
+    /// session's own.
+    pub fn side_sequence(&self) -> SeedSequence {
+        let mut rng = seeded(self.session_seed);
+        rng.set_stream(1);
+        SeedSequence { session_seed: self.session_seed, rng }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequences_repeat_for_the_same_session_seed() {
+        let mut first = SeedSequence::new(42);
+        let mut second = SeedSequence::new(42);
+        for _ in 0..10 {
+            assert_eq!(first.next_seed(), second.next_seed());
+        }
+        assert_ne!(SeedSequence::new(42).next_seed(), SeedSequence::new(43).next_seed());
+    }
+
+    #[test]
+    fn side_sequences_leave_the_session_alone() {
+        let mut session = SeedSequence::new(42);
+        let mut side = session.side_sequence();
+        let side_seeds: Vec<u64> = (0..10).map(|_| side.next_seed()).collect();
+        let session_seeds: Vec<u64> = (0..10).map(|_| session.next_seed()).collect();
+        assert_eq!(session_seeds, (0..10).scan(SeedSequence::new(42), |seeds, _| Some(seeds.next_seed())).collect::<Vec<_>>());
+        assert!(side_seeds.iter().all(|seed| !session_seeds.contains(seed)));
+
+        let mut again = SeedSequence::new(42).side_sequence();
+        assert_eq!(side_seeds, (0..10).map(|_| again.next_seed()).collect::<Vec<_>>());
+        assert_eq!(again.session_seed(), 42);
+    }
+}
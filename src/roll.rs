@@ -1,20 +1,561 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use chrono::{DateTime, Utc};
 use futures::Future;
-use futures::future::{lazy, ok, err};
-use rfyl;
+use futures::future::lazy;
+use rand::Rng;
+
+use dice::{self, is_false, Expr, Faces};
+use format;
+use rng::{self, SeedSequence};
+use tables::{self, Table, TableError};
+pub use dice::{CheckResult, CritDamage, EvalOptions, Mode, PoolResult, Span, Subtotal, Term, MAX_REPEAT};
+
+/// How to roll: the options the dice are evaluated with, and the tables that can be
+/// rolled on, like `table:encounters`, by name
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RollOptions {
+    pub eval: EvalOptions,
+    pub tables: Arc<BTreeMap<String, Table>>,
+}
+
+/// What went wrong while rolling an input: either its dice, or a table it rolled on.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+    Dice(dice::ErrorKind),
+    Table(TableError),
+}
+
+/// An error produced while rolling an input, carrying the span of the input
+/// responsible for it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RollError {
+    pub kind: ErrorKind,
+    pub span: Span,
+}
+
+impl RollError {
+    pub fn new<K: Into<ErrorKind>>(kind: K, span: Span) -> RollError {
+        RollError { kind: kind.into(), span }
+    }
+}
+
+impl From<dice::ErrorKind> for ErrorKind {
+    fn from(kind: dice::ErrorKind) -> ErrorKind {
+        ErrorKind::Dice(kind)
+    }
+}
+
+impl From<TableError> for ErrorKind {
+    fn from(error: TableError) -> ErrorKind {
+        ErrorKind::Table(error)
+    }
+}
+
+impl From<dice::RollError> for RollError {
+    fn from(error: dice::RollError) -> RollError {
+        RollError::new(error.kind, error.span)
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ErrorKind::Dice(ref kind) => kind.fmt(f),
+            ErrorKind::Table(ref error) => error.fmt(f),
+        }
+    }
+}
+
+impl fmt::Display for RollError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (at {}..{})", self.kind, self.span.start, self.span.end)
+    }
+}
+
+impl Error for RollError {}
 
 /// The outcome of a roll, tagged with the description of the roll that generated it.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct RollOutcome {
     pub descriptor: String,
+    /// The macro rolled, like `longsword` for `#longsword`. The descriptor is then the
+    /// macro's expression.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub macro_name: Option<String>,
+    /// The descriptor with its variables replaced by their values, like `1d20 + 3` for
+    /// `1d20 + @str_mod`, if it had any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resolved: Option<String>,
+    /// How the dice were counted, like `Mode::Critical` for `2d6 + 3 crit`
+    #[serde(default, skip_serializing_if = "is_normal")]
+    pub mode: Mode,
     pub outcome: i32,
+    /// The text of the entry rolled, like `3 goblins`, if the roll was on a table, like
+    /// `table:encounters`. The outcome is then the number rolled on the table.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    /// Every term of the expression, including the faces each group of dice rolled
+    pub terms: Vec<Term>,
+    /// When the roll was made
+    #[serde(default = "unknown_time")]
+    pub timestamp: DateTime<Utc>,
+    /// The seed the dice were rolled from, if they were rolled from a seed.
+    /// Rolling the descriptor with this seed gives the same dice again.
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// The successes counted, if the expression rolled any pools, like `8d10>=8`.
+    /// The outcome is then the number of successes left after failures.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pool: Option<PoolResult>,
+    /// How the roll fared, if it was checked against a target, like `1d20 + 5 >= 15`.
+    /// The outcome is still the value of the roll.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub check: Option<CheckResult>,
+    /// Whether a kept die rolled a critical hit, like a natural 20 on a d20
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub critical: bool,
+    /// Whether a kept die rolled a fumble, like a natural 1 on a d20
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub fumble: bool,
+    /// How much of the outcome each tag accounts for, if the expression had any, like
+    /// `5 slashing` and `7 fire` for `1d8[slashing] + 2d6[fire]`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub subtotals: Vec<Subtotal>,
+    /// The batch the roll was part of, if it was rolled along with others from a
+    /// single input, like `6x 4d6dl1`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch: Option<Batch>,
+}
+
+/// A group of rolls made from a single input, like `6x 4d6dl1` or `1d20 + 5; 1d8 + 3`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Batch {
+    /// The whole input
+    pub input: String,
+    /// The seed the seeds of the batch's rolls were drawn from
+    pub seed: u64,
+}
+
+/// An expression parsed from an input, along with how the input asked for it to be rolled
+#[derive(Debug, Clone, PartialEq)]
+pub struct Parsed<'a> {
+    /// The macro rolled, like `longsword` for `#longsword`, if it was one
+    pub macro_name: Option<&'a str>,
+    /// The table rolled on, like `encounters` for `table:encounters`, if it was one.
+    /// The expression is then the dice rolled on the table.
+    pub table: Option<&'a str>,
+    pub expr: Expr,
+    /// The mode asked for with a suffix, like the `crit` in `2d6 + 3 crit`, if any
+    pub mode: Option<Mode>,
+}
+
+/// One statement of an input, like `6x 4d6dl1` in `6x 4d6dl1; 1d20`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Statement<'a> {
+    /// The expression to roll
+    pub expression: &'a str,
+    /// How many times to roll it
+    pub times: u32,
+    /// The byte offset of the expression in the input
+    pub offset: usize,
+}
+
+/// A kind of die named by the input, like `@hit = d{miss=0,hit=1,crit=2}`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Definition<'a> {
+    /// The name to roll the die by, like `hit` for `3@hit`
+    pub name: &'a str,
+    pub faces: Faces,
+}
+
+fn is_normal(mode: &Mode) -> bool {
+    *mode == Mode::Normal
+}
+
+/// The time given to rolls saved before times were recorded
+fn unknown_time() -> DateTime<Utc> {
+    DateTime::from(UNIX_EPOCH)
+}
+
+impl RollOutcome {
+    /// Describe how the outcome was reached, like `3d6 [6, 1, 4] + 2`, followed by how
+    /// much each tag accounts for if it had any, like
+    /// `1d8 [5] slashing + 2d6 [3, 4] fire = 5 slashing, 7 fire`.
+    pub fn breakdown(&self) -> String {
+        match format::subtotals(self) {
+            Some(subtotals) => format!("{} = {}", format::breakdown(&self.terms), subtotals),
+            None => format::breakdown(&self.terms),
+        }
+    }
+}
+
+/// Parse and roll an expression right away, from a random seed, with the default options.
+pub fn roll(s: &str) -> Result<RollOutcome, RollError> {
+    roll_seeded(s, rng::random_seed(), &RollOptions::default())
+}
+
+/// Parse and roll an expression from the given seed. The same expression and seed
+/// always roll the same dice.
+pub fn roll_seeded(s: &str, seed: u64, options: &RollOptions) -> Result<RollOutcome, RollError> {
+    let mut outcome = roll_with(s, &mut rng::seeded(seed), options)?;
+    outcome.seed = Some(seed);
+    Ok(outcome)
 }
 
-pub fn lazy_roll(s: String) -> Box<Future<Item = RollOutcome, Error = ()>> {
-    Box::new(lazy(|| {
-        let rolls = match rfyl::roll(s) {
-            Ok(v) => v,
-            Err(_) => return err(())
-        };
-        ok( RollOutcome { descriptor: rolls.get_rolls_formula_string_as_infix(), outcome: rolls.get_result() }) 
+/// Parse and roll an expression using any source of randomness.
+/// A suffix like `crit` rolls in that mode instead of the options' mode.
+pub fn roll_with<R: Rng + ?Sized>(s: &str, rng: &mut R, options: &RollOptions) -> Result<RollOutcome, RollError> {
+    roll_nested(s, rng, options, 0)
+}
+
+/// Roll an expression like `roll_with`, as one of the inline rolls of a table's entry
+/// that's `depth` tables deep, so tables that roll on each other stop at
+/// `tables::MAX_DEPTH`.
+pub(crate) fn roll_nested<R: Rng + ?Sized>(s: &str, rng: &mut R, options: &RollOptions, depth: u32)
+    -> Result<RollOutcome, RollError>
+{
+    let Parsed { macro_name, table, expr, mode } = parse(s, options)?;
+    if table.is_some() && depth > tables::MAX_DEPTH {
+        return Err(RollError::new(TableError::TooDeep(tables::MAX_DEPTH), whole(s)));
+    }
+    let with_mode;
+    let options = match mode {
+        Some(mode) if mode != options.eval.mode => {
+            with_mode = RollOptions { eval: EvalOptions { mode, ..options.eval.clone() }, ..options.clone() };
+            &with_mode
+        },
+        _ => options,
+    };
+    let resolved = dice::resolve(&expr, &options.eval);
+    let evaluation = dice::evaluate(&resolved, rng, &options.eval).map_err(|e| match (macro_name, table) {
+        (None, None) => RollError::from(e),
+        _ => RollError::new(e.kind, whole(s)),
+    })?;
+    // A table's entry is looked up, and its inline rolls made, after its dice are rolled
+    let text = match table {
+        Some(name) => Some(tables::entry_text(name, evaluation.value, rng, options, depth)
+            .map_err(|kind| RollError::new(kind, whole(s)))?),
+        None => None,
+    };
+    let descriptor = match table {
+        Some(name) => format!("{}{}", tables::PREFIX, name),
+        None => expr.to_string(),
+    };
+    let resolved = resolved.to_string();
+    Ok(RollOutcome {
+        macro_name: macro_name.map(str::to_string),
+        resolved: if resolved != descriptor { Some(resolved) } else { None },
+        descriptor,
+        mode: options.eval.mode,
+        outcome: evaluation.value,
+        text,
+        terms: evaluation.terms,
+        timestamp: Utc::now(),
+        seed: None,
+        pool: evaluation.pool,
+        check: evaluation.check,
+        critical: evaluation.critical,
+        fumble: evaluation.fumble,
+        subtotals: evaluation.subtotals,
+        batch: None,
+    })
+}
+
+/// Split an input into its statements, which are separated by `;`. Each statement
+/// may start with a number of times to roll it, like `6x 4d6dl1`. Empty statements
+/// are skipped.
+pub fn statements(s: &str) -> Result<Vec<Statement<'_>>, RollError> {
+    let mut statements = Vec::new();
+    let mut offset = 0;
+    for part in s.split(';') {
+        if !part.trim().is_empty() {
+            statements.push(statement(part, offset)?);
+        }
+        offset += part.len() + 1;
+    }
+    if statements.is_empty() {
+        return Err(RollError::new(dice::ErrorKind::EmptyInput, Span::new(0, s.len())));
+    }
+    Ok(statements)
+}
+
+/// Parse a single statement that starts `offset` bytes into the input.
+fn statement(part: &str, offset: usize) -> Result<Statement<'_>, RollError> {
+    // The offset of a piece of the statement that runs to its end
+    let offset_of = |rest: &str| offset + part.len() - rest.len();
+    let trimmed = part.trim_start();
+    let digits = trimmed.find(|c: char| !c.is_ascii_digit()).unwrap_or(trimmed.len());
+    let after = trimmed[digits..].trim_start();
+    let expression = match after.chars().next() {
+        Some('x') | Some('X') | Some('×') if digits > 0 => &after[after.chars().next().unwrap().len_utf8()..],
+        _ => return Ok(Statement { expression: part, times: 1, offset }),
+    };
+    let times = trimmed[..digits].parse().ok().filter(|times| (1..=MAX_REPEAT).contains(times))
+        .ok_or_else(|| {
+            let start = offset_of(trimmed);
+            RollError::new(dice::ErrorKind::InvalidRepeat, Span::new(start, start + digits))
+        })?;
+    Ok(Statement { expression, times, offset: offset_of(expression) })
+}
+
+/// Read the definition of a named die, like `@hit = d{miss=0,hit=1,crit=2}`, if the input
+/// is one. Only a lone `@name`, then `=`, then dice like `d6`, `dF` or `d{...}` define a
+/// die; anything else, like the check `@str_mod = 3`, is left to be rolled.
+pub fn definition(s: &str) -> Option<Result<Definition<'_>, RollError>> {
+    let trimmed = s.trim();
+    let name_and_rest = trimmed.strip_prefix('@')?;
+    let length = name_and_rest.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(name_and_rest.len());
+    let name = &name_and_rest[..length];
+    let faces = name_and_rest[length..].trim_start().strip_prefix('=')?.trim_start();
+    let mut chars = faces.chars();
+    let is_dice = match (chars.next(), chars.next()) {
+        (Some('d'), Some(c)) | (Some('D'), Some(c)) => c.is_ascii_digit() || c == '{' || c == 'F' || c == 'f',
+        _ => false,
+    };
+    if name.is_empty() || !is_dice {
+        return None;
+    }
+    let offset = s.trim_end().len() - faces.len();
+    Some(dice::parse_faces(faces).map(|faces| Definition { name, faces }).map_err(|e| {
+        RollError::new(e.kind, Span::new(e.span.start + offset, e.span.end + offset))
     }))
-}
\ No newline at end of file
+}
+
+/// Roll every statement of an input, like `6x 4d6dl1; 1d20`, in order. An input of a
+/// single roll is rolled from the seed, just as `roll_seeded` would; otherwise each
+/// roll draws its seed from a `SeedSequence` started from the seed, and is marked
+/// as part of the batch. Errors point into the whole input.
+pub fn roll_batch(s: &str, seed: u64, options: &RollOptions) -> Result<Vec<RollOutcome>, RollError> {
+    let statements = statements(s)?;
+    let roll = |statement: &Statement, seed| roll_seeded(statement.expression, seed, options).map_err(|e| {
+        RollError::new(e.kind, Span::new(e.span.start + statement.offset, e.span.end + statement.offset))
+    });
+    if let [statement] = statements[..] {
+        if statement.times == 1 {
+            return roll(&statement, seed).map(|outcome| vec![outcome]);
+        }
+    }
+
+    let batch = Batch { input: s.trim().to_string(), seed };
+    let mut seeds = SeedSequence::new(seed);
+    let mut outcomes = Vec::new();
+    for statement in &statements {
+        for _ in 0..statement.times {
+            let mut outcome = roll(statement, seeds.next_seed())?;
+            outcome.batch = Some(batch.clone());
+            outcomes.push(outcome);
+        }
+    }
+    Ok(outcomes)
+}
+
+/// Parse an expression, the expression of the macro it names, like `#longsword`, or
+/// the dice of the table it names, like `table:encounters`, and the suffix for the mode
+/// to roll it in, like the `crit` in `2d6 + 3 crit`, if it has one.
+///
+/// Errors in a macro's expression or a table's dice point at the whole of `s`, since
+/// they aren't part of `s`.
+pub fn parse<'a>(s: &'a str, options: &RollOptions) -> Result<Parsed<'a>, RollError> {
+    let (s, mode) = split_mode(s);
+    if let Some(name) = tables::table_name(s) {
+        let expression = options.tables.get(name).map(Table::expression)
+            .ok_or_else(|| RollError::new(TableError::Unknown(name.to_string()), whole(s)))?;
+        let expr = dice::parse(&expression).map_err(|e| RollError::new(e.kind, whole(s)))?;
+        return Ok(Parsed { macro_name: None, table: Some(name), expr, mode });
+    }
+    let name = match s.trim() {
+        name if name.starts_with('#') => name[1..].trim(),
+        _ => return Ok(Parsed { macro_name: None, table: None, expr: dice::parse(s)?, mode }),
+    };
+    let expression = options.eval.macros.get(name)
+        .ok_or_else(|| RollError::new(dice::ErrorKind::UnknownMacro(name.to_string()), whole(s)))?;
+    let expr = dice::parse(expression).map_err(|e| RollError::new(e.kind, whole(s)))?;
+    Ok(Parsed { macro_name: Some(name), table: None, expr, mode })
+}
+
+/// Split the suffix for a mode, like the ` crit` in `2d6 + 3 crit`, off the end of an
+/// expression, if it has one.
+fn split_mode(s: &str) -> (&str, Option<Mode>) {
+    let trimmed = s.trim_end();
+    let suffix = trimmed.rsplit(char::is_whitespace).next().unwrap_or("");
+    let rest = &trimmed[..trimmed.len() - suffix.len()];
+    match Mode::from_suffix(suffix) {
+        // The suffix must be written after the expression, with a space between them
+        Some(mode) if !rest.trim().is_empty() => (rest, Some(mode)),
+        _ => (s, None),
+    }
+}
+
+/// The span of the whole of a string, less surrounding whitespace
+fn whole(s: &str) -> Span {
+    let start = s.len() - s.trim_start().len();
+    Span::new(start, s.trim_end().len().max(start))
+}
+
+/// Roll an expression from the given seed as a future, for use from an event loop.
+pub fn lazy_roll(s: String, seed: u64, options: RollOptions) -> Box<dyn Future<Item = RollOutcome, Error = RollError>> {
+    Box::new(lazy(move || roll_seeded(&s, seed, &options)))
+}
+
+/// Roll every statement of an input from the given seed as a future, like `roll_batch`.
+pub fn lazy_roll_batch(s: String, seed: u64, options: RollOptions) -> Box<dyn Future<Item = Vec<RollOutcome>, Error = RollError>> {
+    Box::new(lazy(move || roll_batch(&s, seed, &options)))
+}
+
+/// Re-derive a session from its seed and the inputs rolled in it, in order.
+///
+/// Each input takes the next seed from the session's [`SeedSequence`] whether or not
+/// it rolls successfully, just as it did in the session, so failed inputs must be
+/// included to reproduce the rolls after them. Inputs of several rolls, like
+/// `6x 4d6dl1`, give all their outcomes in order. Apart from their timestamps, the
+/// outcomes are identical to the originals, provided they're replayed with the same
+/// options. Rolls made apart from the inputs, like initiative, take their seeds from
+/// [`SeedSequence::side_sequence`] instead, so they needn't be replayed.
+pub fn replay<I>(session_seed: u64, inputs: I, options: &RollOptions) -> Vec<Result<RollOutcome, RollError>>
+    where I: IntoIterator, I::Item: AsRef<str>
+{
+    let mut seeds = SeedSequence::new(session_seed);
+    let mut outcomes = Vec::new();
+    for input in inputs {
+        match roll_batch(input.as_ref(), seeds.next_seed(), options) {
+            Ok(batch) => outcomes.extend(batch.into_iter().map(Ok)),
+            Err(e) => outcomes.push(Err(e)),
+        }
+    }
+    outcomes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tables::Entry;
+
+    /// Options with weighted tables that each roll one die, with an entry for every face
+    fn with_tables(tables: Vec<(&'static str, Vec<&'static str>)>) -> RollOptions {
+        let tables = tables.into_iter().map(|(name, entries)| {
+            let entries = entries.into_iter().map(|text| Entry { range: None, weight: None, text: text.to_string() });
+            (name.to_string(), Table { name: name.to_string(), dice: None, entries: entries.collect() })
+        });
+        RollOptions { tables: Arc::new(tables.collect()), ..RollOptions::default() }
+    }
+
+    /// The outcomes with their timestamps cleared, since those differ between rolls
+    fn timeless(outcomes: Vec<Result<RollOutcome, RollError>>) -> Vec<Result<RollOutcome, RollError>> {
+        outcomes.into_iter().map(|outcome| outcome.map(|outcome| RollOutcome { timestamp: unknown_time(), ..outcome }))
+            .collect()
+    }
+
+    #[test]
+    fn the_same_seed_rolls_the_same_dice() {
+        let options = RollOptions::default();
+        let first = roll_seeded("4d6dl1 + 1d8!", 42, &options).unwrap();
+        let second = roll_seeded("4d6dl1 + 1d8!", 42, &options).unwrap();
+        assert_eq!(first.terms, second.terms);
+        assert_eq!(first.seed, Some(42));
+    }
+
+    #[test]
+    fn replaying_a_session_gives_the_same_outcomes() {
+        let inputs = ["1d20 + 5", "nonsense (", "6x 4d6dl1", "1d20 + 5 vs 15; 2d6 crit"];
+        let options = RollOptions::default();
+        let first = timeless(replay(1234, &inputs, &options));
+        assert_eq!(first, timeless(replay(1234, &inputs, &options)));
+        assert_eq!(first.len(), 1 + 1 + 6 + 2);
+        assert!(first[1].is_err());
+
+        // Each input takes its own seed, so the rolls after a failed input don't change
+        let mut seeds = SeedSequence::new(1234);
+        let seeds: Vec<u64> = (0..4).map(|_| seeds.next_seed()).collect();
+        assert_eq!(first[0].as_ref().unwrap().seed, Some(seeds[0]));
+        assert_eq!(first[2].as_ref().unwrap().batch.as_ref().unwrap().seed, seeds[2]);
+    }
+
+    #[test]
+    fn statements_and_repeats() {
+        assert_eq!(statements("6x 4d6dl1; 1d20").unwrap(), vec![
+            Statement { expression: " 4d6dl1", times: 6, offset: 2 },
+            Statement { expression: " 1d20", times: 1, offset: 10 },
+        ]);
+        assert_eq!(statements(" ; ").unwrap_err().kind, ErrorKind::Dice(dice::ErrorKind::EmptyInput));
+        let e = statements("1d6; 0x 1d20").unwrap_err();
+        assert_eq!((e.kind, e.span), (ErrorKind::Dice(dice::ErrorKind::InvalidRepeat), Span::new(5, 6)));
+    }
+
+    #[test]
+    fn batches_mark_their_rolls() {
+        let options = RollOptions::default();
+        let single = roll_batch("1d20", 7, &options).unwrap();
+        assert_eq!(single.len(), 1);
+        assert_eq!(single[0].batch, None);
+        let batch = roll_batch("3x 1d20; 1d8", 7, &options).unwrap();
+        assert_eq!(batch.len(), 4);
+        assert!(batch.iter().all(|outcome| outcome.batch == Some(Batch { input: "3x 1d20; 1d8".to_string(), seed: 7 })));
+        let e = roll_batch("1d20; 1d8 +", 7, &options).unwrap_err();
+        assert_eq!(e.span, Span::new(11, 11));
+    }
+
+    #[test]
+    fn definitions_name_dice() {
+        let hit = definition(" @hit = d{miss=0,hit=1,crit=2} ").unwrap().unwrap();
+        assert_eq!(hit.name, "hit");
+        assert_eq!((hit.faces.sides(), hit.faces.label(2)), (3, Some("crit".to_string())));
+        assert_eq!(definition("@fate=dF").unwrap().unwrap().faces, Faces::Fudge);
+        // Checks against a variable, and anything else, are left to be rolled
+        assert_eq!(definition("@str_mod = 3"), None);
+        assert_eq!(definition("1d20 + @str_mod = 3"), None);
+        assert_eq!(definition("@ = d6"), None);
+        let e = definition("@hit = d6 + 1").unwrap().unwrap_err();
+        assert_eq!(e.span, Span::new(10, 11));
+    }
+
+    #[test]
+    fn a_suffix_picks_the_mode() {
+        let outcome = roll_seeded("2d6 + 3 max", 0, &RollOptions::default()).unwrap();
+        assert_eq!((outcome.descriptor.as_str(), outcome.mode, outcome.outcome), ("2d6 + 3", Mode::Maximize, 15));
+        let mut options = RollOptions::default();
+        options.eval.mode = Mode::Maximize;
+        assert_eq!(roll_seeded("2d6 + 3 avg", 0, &options).unwrap().outcome, 10);
+        // A lone word is rolled as it is, not taken for a suffix
+        assert!(roll_seeded("max", 0, &RollOptions::default()).is_err());
+    }
+
+    #[test]
+    fn macros_roll_their_expression() {
+        let mut options = RollOptions::default();
+        options.eval.macros.insert("longsword".to_string(), "1d20 + 7".to_string());
+        let outcome = roll_seeded("#longsword", 0, &options).unwrap();
+        assert_eq!(outcome.macro_name, Some("longsword".to_string()));
+        assert_eq!(outcome.descriptor, "1d20 + 7");
+        let e = roll_seeded("  #dagger", 0, &options).unwrap_err();
+        assert_eq!(e.kind, ErrorKind::Dice(dice::ErrorKind::UnknownMacro("dagger".to_string())));
+        assert_eq!(e.span, Span::new(2, 9));
+    }
+
+    #[test]
+    fn tables_roll_their_entries() {
+        let options = with_tables(vec![
+            ("encounters", vec!["[[1d1 + 2]] goblins", "An ogre named [[table:names]]"]),
+            ("names", vec!["Grok"]),
+        ]);
+        for seed in 0..20 {
+            let outcome = roll_seeded("table:encounters", seed, &options).unwrap();
+            let expected = if outcome.outcome == 1 { "3 goblins" } else { "An ogre named Grok" };
+            assert_eq!(outcome.text.as_deref(), Some(expected));
+        }
+        let e = roll_seeded("table:treasure", 0, &options).unwrap_err();
+        assert_eq!(e.kind, ErrorKind::Table(TableError::Unknown("treasure".to_string())));
+    }
+
+    #[test]
+    fn tables_that_roll_on_themselves_stop() {
+        let options = with_tables(vec![("loop", vec!["[[table:loop]]"])]);
+        let e = roll_seeded("table:loop", 0, &options).unwrap_err();
+        assert_eq!(e.kind, ErrorKind::Table(TableError::TooDeep(tables::MAX_DEPTH)));
+        assert_eq!(e.span, Span::new(0, 10));
+    }
+}
@@ -0,0 +1,280 @@
+//! User settings, read from `config.toml` in the config directory.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Deserializer, Serializer};
+use serde::de::Error;
+use toml;
+
+use dice::{default_crits, CritDamage, CritRule, EvalOptions, Faces, Mode};
+use paths;
+
+/// Everything the user can configure. Missing settings take their default values.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// The most rolls to keep in the history
+    pub history_limit: usize,
+    /// Seed every session from this, so each session rolls the same dice.
+    /// Sessions are seeded at random if it's not set.
+    pub seed: Option<u64>,
+    /// The most times a single die may explode
+    pub explosion_limit: u32,
+    /// The most times the dice of a single roll may be rerolled altogether
+    pub reroll_limit: u32,
+    /// How many successes a pool needs, after failures, to be exceptional
+    pub exceptional_successes: u32,
+    /// How the dice of a critical hit are rolled: `"double-dice"` rolls twice as many,
+    /// and `"max-plus-roll"` adds the most they could roll to a normal roll
+    pub crit_damage: CritDamage,
+    /// Show the results of Fudge dice on the FATE ladder, like `Great (+4)`
+    pub fate_ladder: bool,
+    /// The character whose variables are used when starting up, if any
+    pub profile: Option<String>,
+    /// Dice that can be rolled by name, like `3@hit`, written the way they are in
+    /// expressions: `hit = "d{miss=0,miss=0,miss=0,hit=1,hit=1,crit=2}"`
+    pub dice: BTreeMap<String, Faces>,
+    /// Which faces are critical hits and fumbles, by the number of sides of the die.
+    /// Written by the die, as in `[crits.d20]` with `critical = ">=19"` and `fumble = "1"`.
+    #[serde(serialize_with = "serialize_crits", deserialize_with = "deserialize_crits")]
+    pub crits: BTreeMap<u32, CritRule>,
+    /// Expressions that can be rolled by name, like `#longsword`: `longsword = "1d20 + 7"`
+    pub macros: BTreeMap<String, String>,
+    /// Each character's variables, like `@str_mod`, by the character's name. Written
+    /// as `[profiles.Thorin]` with `str_mod = 3`.
+    pub profiles: BTreeMap<String, BTreeMap<String, i32>>,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            history_limit: 1000,
+            seed: None,
+            explosion_limit: EvalOptions::default().explosion_limit,
+            reroll_limit: EvalOptions::default().reroll_limit,
+            exceptional_successes: EvalOptions::default().exceptional_successes,
+            crit_damage: CritDamage::default(),
+            fate_ladder: false,
+            profile: None,
+            dice: BTreeMap::new(),
+            crits: default_crits(),
+            macros: BTreeMap::new(),
+            profiles: BTreeMap::new(),
+        }
+    }
+}
+
+impl Settings {
+    /// Where the settings are stored, if there is anywhere to store them.
+    pub fn path() -> Option<PathBuf> {
+        paths::config_dir().map(|dir| dir.join("config.toml"))
+    }
+
+    /// Load the user's settings, using the defaults if there aren't any.
+    pub fn load() -> io::Result<Settings> {
+        match Settings::path() {
+            Some(path) => Settings::load_from(&path),
+            None => Ok(Settings::default()),
+        }
+    }
+
+    /// The variables of a character, or none if there's no character by that name.
+    pub fn variables(&self, profile: Option<&str>) -> BTreeMap<String, i32> {
+        profile.and_then(|profile| self.profiles.get(profile)).cloned().unwrap_or_default()
+    }
+
+    /// The options to evaluate rolls with, using the starting character's variables
+    pub fn eval_options(&self) -> EvalOptions {
+        EvalOptions {
+            explosion_limit: self.explosion_limit,
+            reroll_limit: self.reroll_limit,
+            exceptional_successes: self.exceptional_successes,
+            dice: self.dice.clone(),
+            crits: self.crits.clone(),
+            macros: self.macros.clone(),
+            variables: self.variables(self.profile.as_deref()),
+            mode: Mode::Normal,
+            crit_damage: self.crit_damage,
+        }
+    }
+
+    /// Load settings from a file, using the defaults if it doesn't exist.
+    pub fn load_from(path: &Path) -> io::Result<Settings> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(Settings::default()),
+            Err(e) => return Err(e),
+        };
+        toml::from_str(&text).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+    }
+
+    /// Name a kind of die, so it can be rolled like `3@hit`, and save it to the user's
+    /// settings, replacing any dice already by that name.
+    pub fn save_dice(&mut self, name: &str, faces: Faces) -> io::Result<()> {
+        match Settings::path() {
+            Some(path) => self.save_dice_to(&path, name, faces),
+            None => Err(io::Error::new(ErrorKind::NotFound, "there's nowhere to keep settings")),
+        }
+    }
+
+    /// Name a kind of die and save it to the settings in a file. Only the dice's line of
+    /// the file is written, so the rest of it, comments and all, is kept as it was.
+    /// The file is replaced in one step, so a crash can't leave it half written.
+    pub fn save_dice_to(&mut self, path: &Path, name: &str, faces: Faces) -> io::Result<()> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let text = with_dice(&text, name, &faces);
+        // Make sure the file still reads back as settings, with the new dice in them
+        let settings: Settings = toml::from_str(&text).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        if settings.dice.get(name) != Some(&faces) {
+            return Err(io::Error::new(ErrorKind::InvalidData, format!("couldn't add `{}` to the dice in {}", name, path.display())));
+        }
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let temporary = path.with_extension("toml.tmp");
+        {
+            let mut file = File::create(&temporary)?;
+            file.write_all(text.as_bytes())?;
+            file.sync_all()?;
+        }
+        fs::rename(&temporary, path)?;
+        self.dice.insert(name.to_string(), faces);
+        Ok(())
+    }
+}
+
+/// The text of a settings file with the named dice set, in the `[dice]` table if it has
+/// one, replacing the line of any dice already by that name.
+fn with_dice(text: &str, name: &str, faces: &Faces) -> String {
+    let key = if name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        name.to_string()
+    } else {
+        toml::Value::String(name.to_string()).to_string()
+    };
+    let line = format!("{} = {}", key, toml::Value::String(format!("d{}", faces)));
+
+    let mut lines: Vec<&str> = text.lines().collect();
+    let header = |line: &str| line.trim().starts_with('[');
+    let table = lines.iter().position(|line| line.split('#').next().unwrap_or("").trim() == "[dice]");
+    let table = match table {
+        Some(table) => table,
+        None => {
+            let mut text = text.trim_end().to_string();
+            if !text.is_empty() {
+                text.push_str("\n\n");
+            }
+            return format!("{}[dice]\n{}\n", text, line);
+        },
+    };
+    let end = lines[table + 1..].iter().position(|line| header(line)).map_or(lines.len(), |end| table + 1 + end);
+    let existing = (table + 1..end).find(|&index| {
+        lines[index].split('=').next().map(|before| before.trim()) == Some(key.as_str())
+    });
+    match existing {
+        Some(index) => lines[index] = &line,
+        None => {
+            // After the table's last setting, ahead of any blank lines before the next table
+            let last = (table..end).rev().find(|&index| !lines[index].trim().is_empty()).unwrap_or(table);
+            lines.insert(last + 1, &line);
+        },
+    }
+    let mut text = lines.join("\n");
+    text.push('\n');
+    text
+}
+
+
+fn serialize_crits<S: Serializer>(crits: &BTreeMap<u32, CritRule>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(crits.iter().map(|(sides, rule)| (format!("d{}", sides), rule)))
+}
+
+fn deserialize_crits<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<u32, CritRule>, D::Error> {
+    let crits = BTreeMap::<String, CritRule>::deserialize(deserializer)?;
+    crits.into_iter().map(|(die, rule)| {
+        match die.trim_start_matches('d').parse() {
+            Ok(sides) if sides > 0 => Ok((sides, rule)),
+            _ => Err(D::Error::custom(format!("`{}` isn't a die like `d20`", die))),
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dice::{Compare, CompareOp};
+
+    #[test]
+    fn missing_settings_are_the_defaults() {
+        let path = ::std::env::temp_dir().join("d20roll-settings-missing.toml");
+        assert_eq!(Settings::load_from(&path).unwrap(), Settings::default());
+        assert_eq!(toml::from_str::<Settings>("history_limit = 20").unwrap().explosion_limit, 100);
+    }
+
+    #[test]
+    fn named_dice_are_read_as_written() {
+        let settings: Settings = toml::from_str("[dice]\nfib = \"d{1,1,2,3,5,8}\"").unwrap();
+        assert_eq!(settings.dice["fib"].sides(), 6);
+        assert_eq!(settings.eval_options().dice["fib"], settings.dice["fib"]);
+        assert!(toml::from_str::<Settings>("[dice]\nfib = \"d{}\"").is_err());
+    }
+
+    #[test]
+    fn named_dice_are_saved_in_place() {
+        let path = ::std::env::temp_dir().join(format!("d20roll-settings-{}-saved-dice.toml", ::std::process::id()));
+        fs::write(&path, "# My dice\nhistory_limit = 20\n\n[dice]\nfib = \"d{1,1,2}\" # Old\n\n[macros]\nhit = \"1d20\"\n").unwrap();
+        let mut settings = Settings::load_from(&path).unwrap();
+        settings.save_dice_to(&path, "fib", Faces::Numbered(6)).unwrap();
+        settings.save_dice_to(&path, "hit", Faces::Fudge).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(),
+            "# My dice\nhistory_limit = 20\n\n[dice]\nfib = \"d6\"\nhit = \"dF\"\n\n[macros]\nhit = \"1d20\"\n");
+        assert_eq!(Settings::load_from(&path).unwrap(), settings);
+        fs::remove_file(&path).unwrap();
+
+        // Settings without any dice get a table of them
+        settings.save_dice_to(&path, "hit", Faces::Numbered(4)).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "[dice]\nhit = \"d4\"\n");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn crit_rules_are_read_by_the_die() {
+        let settings: Settings = toml::from_str("[crits.d20]\ncritical = \">=19\"").unwrap();
+        assert_eq!(settings.crits[&20].critical, Some(Compare { op: CompareOp::GreaterEqual, target: 19 }));
+        assert_eq!(settings.crits[&20].fumble, None);
+        assert!(toml::from_str::<Settings>("[crits.twenty]\ncritical = \"20\"").is_err());
+    }
+
+    #[test]
+    fn crits_are_written_by_the_die() {
+        let text = toml::to_string(&Settings::default()).unwrap();
+        assert!(text.contains("[crits.d20]"));
+        assert_eq!(toml::from_str::<Settings>(&text).unwrap(), Settings::default());
+    }
+
+    #[test]
+    fn macros_are_read_by_name() {
+        let settings: Settings = toml::from_str("[macros]\nlongsword = \"1d20 + 7\"").unwrap();
+        assert_eq!(settings.eval_options().macros["longsword"], "1d20 + 7");
+    }
+
+    #[test]
+    fn the_starting_character_gives_the_variables() {
+        let settings: Settings = toml::from_str("profile = \"Thorin\"\n[profiles.Thorin]\nstr_mod = 3").unwrap();
+        assert_eq!(settings.eval_options().variables["str_mod"], 3);
+        assert!(settings.variables(Some("Nobody")).is_empty());
+    }
+
+    #[test]
+    fn crit_damage_is_read_in_kebab_case() {
+        let settings: Settings = toml::from_str("crit_damage = \"max-plus-roll\"").unwrap();
+        assert_eq!(settings.eval_options().crit_damage, CritDamage::MaxPlusRoll);
+    }
+}
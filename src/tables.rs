@@ -0,0 +1,542 @@
+//! Random tables, like loot, encounters or names, rolled with `table:encounters`.
+//!
+//! Tables are read from the `tables` directory beside the settings, one per file, as
+//! TOML, JSON or CSV. Each entry either covers a range of rolls, like `2-4`, or has a
+//! weight, which makes it that many times as likely as an entry of weight 1. The ranges
+//! must cover every roll the table's dice can make. An entry's text can roll dice,
+//! like `[[2d6]] goblins`, or roll on another table, like `[[table:names]]`.
+//!
+//! In TOML, a table with ranges looks like:
+//!
+//! ```toml
+//! dice = "2d6"
+//!
+//! [[entries]]
+//! range = "2-6"
+//! text = "[[1d4]] goblins"
+//!
+//! [[entries]]
+//! range = "7-12"
+//! text = "An ogre named [[table:names]]"
+//! ```
+//!
+//! A CSV table has a header row naming its `range` or `weight` column and its `text`
+//! column, and always rolls one die.
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use csv;
+use rand::Rng;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::Error;
+use serde_json;
+use toml;
+
+use analysis::analyze;
+use dice;
+use paths;
+use roll::{self, EvalOptions, RollOptions};
+
+/// How many tables deep entries may roll on other tables, so tables that refer to
+/// each other can't roll forever
+pub const MAX_DEPTH: u32 = 10;
+
+/// What starts a roll on a table, as in `table:encounters`
+pub const PREFIX: &str = "table:";
+
+/// What went wrong while rolling on a table
+#[derive(Debug, Clone, PartialEq)]
+pub enum TableError {
+    /// A table, like `table:encounters`, that hasn't been loaded
+    Unknown(String),
+    /// A roll on a table that none of its entries cover
+    NoEntry { table: String, roll: i32 },
+    /// Tables whose entries roll on each other more than the given number of tables deep
+    TooDeep(u32),
+}
+
+impl fmt::Display for TableError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TableError::Unknown(ref name) => write!(f, "no table named `{}`", name),
+            TableError::NoEntry { ref table, roll } => write!(f, "table `{}` has no entry for {}", table, roll),
+            TableError::TooDeep(depth) => write!(f, "tables roll on each other more than {} deep", depth),
+        }
+    }
+}
+
+impl error::Error for TableError {}
+
+/// A random table
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Table {
+    /// The name the table is rolled by, like `encounters` for `table:encounters`.
+    /// Tables loaded from a file are named after it unless they say otherwise.
+    #[serde(default)]
+    pub name: String,
+    /// The dice rolled on a table with ranges, like `2d6`. Without them, one die is
+    /// rolled with as many sides as the highest range reaches. Weighted tables always
+    /// roll one die with as many sides as their total weight.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dice: Option<String>,
+    pub entries: Vec<Entry>,
+}
+
+/// One entry of a random table
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    /// The rolls that give this entry, like `2-4`, on a table with ranges
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range: Option<Range>,
+    /// How likely the entry is, compared to the others, on a weighted table. Defaults
+    /// to 1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weight: Option<u32>,
+    /// What the entry says, which may roll dice, like `[[2d6]] goblins`, or on another
+    /// table, like `[[table:names]]`
+    pub text: String,
+}
+
+/// The rolls that give an entry, from `min` to `max` inclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Range {
+    pub min: i32,
+    pub max: i32,
+}
+
+impl Range {
+    /// Whether a roll is in the range
+    pub fn contains(self, roll: i32) -> bool {
+        self.min <= roll && roll <= self.max
+    }
+}
+
+/// Prints the range like `2-4`, or `5` if it's a single roll
+impl fmt::Display for Range {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.min == self.max {
+            write!(f, "{}", self.min)
+        } else {
+            write!(f, "{}-{}", self.min, self.max)
+        }
+    }
+}
+
+/// Ranges are written like `"2-4"`, or as a single roll, like `5` or `"5"`.
+impl Serialize for Range {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Range {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Range, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Notation {
+            Roll(i32),
+            Text(String),
+        }
+        let text = match Notation::deserialize(deserializer)? {
+            Notation::Roll(roll) => return Ok(Range { min: roll, max: roll }),
+            Notation::Text(text) => text,
+        };
+        let text = text.trim();
+        // The first character may be the sign of the lowest roll
+        let (min, max) = match text.char_indices().skip(1).find(|&(_, c)| c == '-') {
+            Some((i, _)) => (&text[..i], &text[i + 1..]),
+            None => (text, text),
+        };
+        match (min.trim().parse(), max.trim().parse()) {
+            (Ok(min), Ok(max)) => Ok(Range { min, max }),
+            _ => Err(D::Error::custom(format!("`{}` isn't a range like `2-4`", text))),
+        }
+    }
+}
+
+impl Table {
+    /// Load a table from a file, reading it as TOML, JSON or CSV by its
+    /// extension. The table isn't validated, since its entries can roll on tables that
+    /// aren't loaded yet; see `validate`.
+    pub fn load(path: &Path) -> io::Result<Table> {
+        let invalid = |e: String| io::Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e));
+        let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or("").to_lowercase();
+        let mut table = match extension.as_str() {
+            "toml" => toml::from_str(&fs::read_to_string(path)?).map_err(|e| invalid(e.to_string()))?,
+            "json" => serde_json::from_str(&fs::read_to_string(path)?).map_err(|e| invalid(e.to_string()))?,
+            "csv" => {
+                let entries = csv::Reader::from_reader(File::open(path)?)
+                    .deserialize()
+                    .collect::<Result<Vec<Entry>, _>>()
+                    .map_err(|e| invalid(e.to_string()))?;
+                Table { name: String::new(), dice: None, entries }
+            },
+            _ => return Err(invalid("tables must be TOML, JSON or CSV files".to_string())),
+        };
+        if table.name.is_empty() {
+            table.name = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("").to_string();
+        }
+        Ok(table)
+    }
+
+    /// Check that the table can be rolled on with the given options, explaining what's
+    /// wrong if it can't. Inline rolls are parsed just as they are when the table is
+    /// rolled on, so they may be macros, roll in a mode or roll on any table in the
+    /// options.
+    pub fn validate(&self, options: &RollOptions) -> Result<(), String> {
+        if self.name.is_empty() || self.name.chars().any(char::is_whitespace) {
+            return Err(format!("`{}` isn't a name a table can be rolled by", self.name));
+        }
+        if self.entries.is_empty() {
+            return Err("the table has no entries".to_string());
+        }
+        let ranges: Vec<Range> = self.entries.iter().filter_map(|entry| entry.range).collect();
+        if ranges.is_empty() {
+            if self.dice.is_some() {
+                return Err("only tables with ranges can choose their dice".to_string());
+            }
+            if self.entries.iter().any(|entry| entry.weight == Some(0)) {
+                return Err("weights must be at least 1".to_string());
+            }
+            if self.total_weight().is_none() {
+                return Err("the weights add up to too much".to_string());
+            }
+        } else if ranges.len() < self.entries.len() {
+            return Err("either every entry needs a range or none can have one".to_string());
+        } else if self.entries.iter().any(|entry| entry.weight.is_some()) {
+            return Err("entries can have a range or a weight, but not both".to_string());
+        }
+        for (i, range) in ranges.iter().enumerate() {
+            if range.min > range.max {
+                return Err(format!("the range `{}-{}` is backwards", range.min, range.max));
+            }
+            if let Some(other) = ranges[..i].iter().find(|other| other.min <= range.max && range.min <= other.max) {
+                return Err(format!("the ranges `{}` and `{}` overlap", other, range));
+            }
+        }
+        if let Some(ref expression) = self.dice {
+            dice::parse(expression).map_err(|e| format!("invalid dice `{}`: {}", expression, e.kind))?;
+        }
+        if !ranges.is_empty() {
+            self.check_coverage(&ranges, options)?;
+        }
+        for entry in &self.entries {
+            let pieces = pieces(&entry.text).ok_or_else(|| format!("`[[` without `]]` in `{}`", entry.text))?;
+            for piece in pieces {
+                if let Piece::Roll(roll) = piece {
+                    roll::parse(roll, options).map_err(|e| format!("invalid roll `[[{}]]`: {}", roll, e.kind))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that every roll the table's dice can make gives an entry. Dice whose odds
+    /// have to be estimated may roll anything from their lowest to their highest.
+    fn check_coverage(&self, ranges: &[Range], options: &RollOptions) -> Result<(), String> {
+        let expression = self.expression();
+        let distribution = analyze(&expression, options)
+            .map_err(|e| format!("invalid dice `{}`: {}", expression, e.kind))?
+            .distribution;
+        let rolls: Vec<i32> = if distribution.is_exact() {
+            distribution.iter().map(|(roll, _)| roll).collect()
+        } else {
+            (distribution.min()..=distribution.max()).collect()
+        };
+        match rolls.into_iter().find(|&roll| !ranges.iter().any(|range| range.contains(roll))) {
+            Some(roll) => Err(format!("`{}` can roll {}, but no entry covers it", expression, roll)),
+            None => Ok(()),
+        }
+    }
+
+    /// The expression rolled to pick an entry, like `2d6` or `1d20`
+    pub fn expression(&self) -> String {
+        match self.dice {
+            Some(ref dice) => dice.clone(),
+            None if self.entries.iter().any(|entry| entry.range.is_some()) => {
+                let highest = self.entries.iter().filter_map(|entry| entry.range).map(|range| range.max).max();
+                format!("1d{}", highest.unwrap_or(1).max(1))
+            },
+            None => format!("1d{}", self.total_weight().unwrap_or(1).max(1)),
+        }
+    }
+
+    /// The entry a roll gives, if any. On a weighted table, a roll of 1 gives the
+    /// first entry and each entry covers as many rolls as its weight.
+    pub fn entry(&self, roll: i32) -> Option<&Entry> {
+        if self.entries.iter().any(|entry| entry.range.is_some()) {
+            return self.entries.iter().find(|entry| entry.range.is_some_and(|range| range.contains(roll)));
+        }
+        let mut reached = 0;
+        self.entries.iter().find(|entry| {
+            reached += i64::from(entry.weight.unwrap_or(1));
+            i64::from(roll) <= reached
+        }).filter(|_| roll >= 1)
+    }
+
+    /// The weights of the entries added up, if they fit in a roll
+    fn total_weight(&self) -> Option<i32> {
+        self.entries.iter().try_fold(0i32, |total, entry| total.checked_add(i32::try_from(entry.weight.unwrap_or(1)).ok()?))
+    }
+}
+
+/// Where tables are kept by default, if there is anywhere to keep them.
+pub fn default_dir() -> Option<PathBuf> {
+    paths::config_dir().map(|dir| dir.join("tables"))
+}
+
+/// Load every table in the default directory, by name. See `load_dir`.
+pub fn load(options: &EvalOptions) -> (BTreeMap<String, Table>, Vec<io::Error>) {
+    match default_dir() {
+        Some(dir) => load_dir(&dir, options),
+        None => (BTreeMap::new(), Vec::new()),
+    }
+}
+
+/// Load every table in a directory, by name, and validate them with the given options
+/// and each other. Files that can't be loaded and tables that aren't valid are skipped,
+/// with an error for each; there are none if the directory doesn't exist.
+pub fn load_dir(dir: &Path, options: &EvalOptions) -> (BTreeMap<String, Table>, Vec<io::Error>) {
+    let mut tables = BTreeMap::new();
+    let mut errors = Vec::new();
+    let mut paths: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect(),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return (tables, errors),
+        Err(e) => return (tables, vec![e]),
+    };
+    paths.sort();
+    let mut files = BTreeMap::new();
+    for path in paths.into_iter().filter(|path| path.is_file()) {
+        match Table::load(&path) {
+            Ok(ref table) if tables.contains_key(&table.name) => errors.push(io::Error::new(ErrorKind::InvalidData,
+                format!("{}: there's already a table named `{}`", path.display(), table.name))),
+            Ok(table) => {
+                files.insert(table.name.clone(), path);
+                tables.insert(table.name.clone(), table);
+            },
+            Err(e) => errors.push(e),
+        }
+    }
+
+    // Entries can roll on any of the tables, so they're only checked once all are loaded.
+    // Skipping a table breaks any that roll on it, so they're checked again until none are skipped.
+    loop {
+        let options = RollOptions { eval: options.clone(), tables: Arc::new(tables.clone()) };
+        let count = tables.len();
+        tables.retain(|name, table| match table.validate(&options) {
+            Ok(()) => true,
+            Err(e) => {
+                errors.push(io::Error::new(ErrorKind::InvalidData, format!("{}: {}", files[name].display(), e)));
+                false
+            },
+        });
+        if tables.len() == count {
+            return (tables, errors);
+        }
+    }
+}
+
+/// The name of the table an input rolls on, like `encounters` for `table:encounters`,
+/// if it rolls on one
+pub fn table_name(s: &str) -> Option<&str> {
+    s.trim().strip_prefix(PREFIX).map(str::trim)
+}
+
+/// The text of the entry a roll gives on the table with the given name, with its
+/// inline rolls made. Dice rolled inline give their result, and tables their entry's
+/// text. `depth` is how many tables deep the table is.
+///
+/// Errors don't point anywhere, since the text isn't part of the input, so only say
+/// what went wrong.
+pub fn entry_text<R: Rng + ?Sized>(table: &str, roll: i32, rng: &mut R, options: &RollOptions, depth: u32)
+    -> Result<String, roll::ErrorKind>
+{
+    let entry = options.tables.get(table).and_then(|rolled| rolled.entry(roll))
+        .ok_or_else(|| TableError::NoEntry { table: table.to_string(), roll })?;
+    let pieces = pieces(&entry.text).ok_or(dice::ErrorKind::UnexpectedToken { found: None, expected: "`]]`" })?;
+    let mut text = String::new();
+    for piece in pieces {
+        match piece {
+            Piece::Text(piece) => text.push_str(piece),
+            Piece::Roll(inline) => {
+                let outcome = roll::roll_nested(inline, rng, options, depth + 1).map_err(|e| e.kind)?;
+                match outcome.text {
+                    Some(ref entry) => text.push_str(entry),
+                    None => text.push_str(&outcome.outcome.to_string()),
+                }
+            },
+        }
+    }
+    Ok(text)
+}
+
+/// A piece of an entry's text
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Piece<'a> {
+    /// Text shown as it is
+    Text(&'a str),
+    /// An inline roll, like the `2d6` of `[[2d6]] goblins`
+    Roll(&'a str),
+}
+
+/// Split an entry's text into plain text and the rolls made inline, or `None` if a
+/// `[[` has no `]]`.
+fn pieces(text: &str) -> Option<Vec<Piece<'_>>> {
+    let mut pieces = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("[[") {
+        let end = start + rest[start..].find("]]")?;
+        if start > 0 {
+            pieces.push(Piece::Text(&rest[..start]));
+        }
+        pieces.push(Piece::Roll(&rest[start + 2..end]));
+        rest = &rest[end + 2..];
+    }
+    if !rest.is_empty() {
+        pieces.push(Piece::Text(rest));
+    }
+    Some(pieces)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    /// An empty directory for a test to keep its tables in
+    fn directory(test: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("d20roll-tables-{}-{}", process::id(), test));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn entry(range: Option<(i32, i32)>, weight: Option<u32>, text: &str) -> Entry {
+        Entry { range: range.map(|(min, max)| Range { min, max }), weight, text: text.to_string() }
+    }
+
+    fn table(dice: Option<&str>, entries: Vec<Entry>) -> Table {
+        Table { name: "test".to_string(), dice: dice.map(str::to_string), entries }
+    }
+
+    #[test]
+    fn ranges_pick_their_entries() {
+        let table = table(Some("2d6"), vec![entry(Some((2, 6)), None, "low"), entry(Some((7, 12)), None, "high")]);
+        assert_eq!(table.expression(), "2d6");
+        assert_eq!(table.entry(6).map(|entry| entry.text.as_str()), Some("low"));
+        assert_eq!(table.entry(7).map(|entry| entry.text.as_str()), Some("high"));
+        assert_eq!(table.entry(13), None);
+    }
+
+    #[test]
+    fn weights_cover_that_many_rolls() {
+        let table = table(None, vec![entry(None, Some(3), "common"), entry(None, None, "rare")]);
+        assert_eq!(table.expression(), "1d4");
+        let texts: Vec<Option<&str>> = (0..=5).map(|roll| table.entry(roll).map(|entry| entry.text.as_str())).collect();
+        assert_eq!(texts, vec![None, Some("common"), Some("common"), Some("common"), Some("rare"), None]);
+    }
+
+    #[test]
+    fn invalid_tables_say_why() {
+        let options = RollOptions::default();
+        let invalid = |table: Table| table.validate(&options).unwrap_err();
+        assert!(invalid(table(None, vec![])).contains("no entries"));
+        assert!(invalid(table(None, vec![entry(Some((1, 3)), None, "a"), entry(Some((3, 4)), None, "b")]))
+            .contains("overlap"));
+        assert!(invalid(table(None, vec![entry(Some((1, 3)), None, "a"), entry(None, None, "b")]))
+            .contains("every entry"));
+        assert!(invalid(table(None, vec![entry(None, Some(0), "a")])).contains("at least 1"));
+        assert!(invalid(table(Some("2d"), vec![entry(Some((1, 1)), None, "a")])).contains("invalid dice"));
+        assert!(invalid(table(None, vec![entry(None, None, "[[1d4 goblins")])).contains("without `]]`"));
+        assert!(invalid(table(None, vec![entry(None, None, "[[#ambush]]")])).contains("#ambush"));
+        assert_eq!(invalid(table(Some("2d6"), vec![entry(Some((2, 6)), None, "a"), entry(Some((8, 12)), None, "b")])),
+                   "`2d6` can roll 7, but no entry covers it");
+        assert!(invalid(table(None, vec![entry(Some((1, 2)), None, "a"), entry(Some((5, 6)), None, "b")]))
+            .contains("`1d6` can roll 3"));
+        // Rolls the dice can't make needn't be covered
+        assert_eq!(table(Some("1d3 * 2"), vec![entry(Some((2, 2)), None, "a"), entry(Some((4, 4)), None, "b"),
+                                               entry(Some((6, 6)), None, "c")]).validate(&options), Ok(()));
+    }
+
+    #[test]
+    fn inline_rolls_are_checked_as_they_are_rolled() {
+        let mut options = RollOptions::default();
+        options.eval.macros.insert("ambush".to_string(), "1d4 + 1".to_string());
+        let table = table(None, vec![entry(None, None, "[[#ambush]] goblins, [[2d6 max]] gold")]);
+        assert_eq!(table.validate(&options), Ok(()));
+    }
+
+    #[test]
+    fn pieces_split_out_inline_rolls() {
+        assert_eq!(pieces("[[1d4]] goblins and [[table:names]]"), Some(vec![
+            Piece::Roll("1d4"),
+            Piece::Text(" goblins and "),
+            Piece::Roll("table:names"),
+        ]));
+        assert_eq!(pieces("plain"), Some(vec![Piece::Text("plain")]));
+        assert_eq!(pieces("[[1d4"), None);
+    }
+
+    #[test]
+    fn directories_of_tables() {
+        let dir = directory("load");
+        fs::write(dir.join("encounters.toml"), "\
+dice = \"1d2\"
+[[entries]]
+range = 1
+text = \"[[1d1 + 1]] goblins\"
+[[entries]]
+range = 2
+text = \"An ogre named [[table:names]]\"
+").unwrap();
+        fs::write(dir.join("names.csv"), "weight,text\n1,Grok\n").unwrap();
+        fs::write(dir.join("loot.json"), "{\"entries\": [{\"text\": \"[[table:treasure]]\"}]}").unwrap();
+        fs::write(dir.join("notes.txt"), "not a table").unwrap();
+
+        let (tables, errors) = load_dir(&dir, &EvalOptions::default());
+        assert_eq!(tables.keys().collect::<Vec<_>>(), vec!["encounters", "names"]);
+        let mut errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        errors.sort();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].ends_with("loot.json: invalid roll `[[table:treasure]]`: no table named `treasure`"));
+        assert!(errors[1].contains("notes.txt"));
+
+        let options = RollOptions { tables: Arc::new(tables), ..RollOptions::default() };
+        let mut rng = ::rng::seeded(0);
+        assert_eq!(entry_text("encounters", 1, &mut rng, &options, 0).unwrap(), "2 goblins");
+        assert_eq!(entry_text("encounters", 2, &mut rng, &options, 0).unwrap(), "An ogre named Grok");
+        assert_eq!(entry_text("encounters", 3, &mut rng, &options, 0).unwrap_err(),
+                   roll::ErrorKind::Table(TableError::NoEntry { table: "encounters".to_string(), roll: 3 }));
+        assert_eq!(load_dir(&dir.join("missing"), &EvalOptions::default()).1.len(), 0);
+    }
+
+    #[test]
+    fn tables_rolling_on_skipped_tables_are_skipped() {
+        let dir = directory("skipped");
+        fs::write(dir.join("hoard.csv"), "weight,text
+1,[[table:loot]]
+").unwrap();
+        fs::write(dir.join("loot.csv"), "weight,text
+1,[[table:gems]]
+").unwrap();
+        fs::write(dir.join("gems.csv"), "weight,text
+0,Ruby
+").unwrap();
+        let (tables, errors) = load_dir(&dir, &EvalOptions::default());
+        assert!(tables.is_empty());
+        let mut errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        errors.sort();
+        assert_eq!(errors.len(), 3);
+        assert!(errors[0].ends_with("gems.csv: weights must be at least 1"));
+        assert!(errors[1].ends_with("hoard.csv: invalid roll `[[table:loot]]`: no table named `loot`"));
+        assert!(errors[2].ends_with("loot.csv: invalid roll `[[table:gems]]`: no table named `gems`"));
+    }
+}